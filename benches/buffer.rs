@@ -0,0 +1,85 @@
+//! Memory use of `--buffer-size`: 16 files of 16 MiB downloaded at once,
+//! unbuffered and with growing write buffers, reporting the peak heap over
+//! each batch and the CPU time it took.
+//!
+//! ```text
+//! cargo bench --bench buffer >/dev/null
+//! ```
+
+mod common;
+
+use mt_downloader::Downloader;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+const FILES: usize = 16;
+const FILE_SIZE: usize = 16 << 20;
+const RUNS: usize = 3;
+
+/// The system allocator, keeping track of the most ever allocated at once.
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(live, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOC: Counting = Counting;
+
+/// Download the batch `RUNS` times writing through `buffer` bytes, and
+/// report the best time and the highest heap peak above where it started.
+async fn run(urls: &[url::Url], buffer: usize) {
+    let peak = &AtomicUsize::new(0);
+    let cpu_before = common::cpu_time();
+    let time = common::best_of(RUNS, || async move {
+        let dir = common::scratch_dir("buffer");
+        let downloader = Downloader::builder()
+            .client(reqwest::Client::builder().no_proxy().build().unwrap())
+            .out_dir(&dir)
+            .concurrency(FILES)
+            .buffer_size(buffer)
+            .quiet(true)
+            .build()
+            .unwrap();
+        let base = LIVE.load(Ordering::Relaxed);
+        PEAK.store(base, Ordering::Relaxed);
+        let results = downloader.download(urls).await;
+        peak.fetch_max(PEAK.load(Ordering::Relaxed) - base, Ordering::Relaxed);
+        assert!(results.iter().all(|r| r.is_ok()));
+        let _ = std::fs::remove_dir_all(&dir);
+    })
+    .await;
+    let cpu = match (cpu_before, common::cpu_time()) {
+        (Some(before), Some(after)) => format!("{:.1?}", (after - before) / RUNS as u32),
+        _ => "n/a".to_string(),
+    };
+    eprintln!(
+        "buffer {buffer:>8}: {time:>7.1?} best of {RUNS}, {cpu:>8} CPU, peak heap {:>6.1} MiB",
+        peak.load(Ordering::Relaxed) as f64 / (1 << 20) as f64
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let urls = common::serve(FILE_SIZE, 64 << 10, Duration::ZERO, FILES).await;
+    eprintln!("{FILES} files of {FILE_SIZE} bytes, all at once");
+    for buffer in [0, 64 << 10, 1 << 20, 4 << 20] {
+        run(&urls, buffer).await;
+    }
+}
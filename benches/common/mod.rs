@@ -0,0 +1,76 @@
+//! A local HTTP server for the benchmarks, so they measure the downloader
+//! rather than the network.
+
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use url::Url;
+
+/// Serve `size` bytes for any path, written `chunk` bytes at a time with
+/// `pause` between them so the client sees many small reads. Returns `count`
+/// URLs with distinct names.
+pub async fn serve(size: usize, chunk: usize, pause: Duration, count: usize) -> Vec<Url> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let body = vec![b'x'; chunk];
+        while let Ok((mut conn, _)) = listener.accept().await {
+            let body = body.clone();
+            tokio::spawn(async move {
+                let mut buf = [0; 4096];
+                let _ = conn.read(&mut buf).await;
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {size}\r\nConnection: close\r\n\r\n"
+                );
+                conn.write_all(head.as_bytes()).await?;
+                let mut left = size;
+                while left > 0 {
+                    let n = left.min(chunk);
+                    conn.write_all(&body[..n]).await?;
+                    left -= n;
+                    if !pause.is_zero() {
+                        tokio::time::sleep(pause).await;
+                    }
+                }
+                conn.shutdown().await
+            });
+        }
+    });
+    (0..count)
+        .map(|i| Url::parse(&format!("http://{addr}/file{i}.bin")).unwrap())
+        .collect()
+}
+
+/// A fresh, empty directory to download into.
+pub fn scratch_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("mt-downloader-bench-{name}"));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Best wall time of `runs` runs of `f`.
+pub async fn best_of<F, Fut>(runs: usize, mut f: F) -> Duration
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let mut best = Duration::MAX;
+    for _ in 0..runs {
+        let started = Instant::now();
+        f().await;
+        best = best.min(started.elapsed());
+    }
+    best
+}
+
+/// CPU time used by this process so far, all threads together, or `None`
+/// where `/proc` doesn't have it.
+pub fn cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // Fields after the parenthesized command name, which may contain spaces
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks: u64 = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    // USER_HZ, which is 100 on every Linux that matters
+    Some(Duration::from_millis(ticks * 10))
+}
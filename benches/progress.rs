@@ -0,0 +1,131 @@
+//! Redraw overhead of progress bars: 50 small files downloaded at once with
+//! bars drawn at the default refresh, at a slow one, and with none at all.
+//!
+//! Bars are drawn into a fake terminal that only counts what it's sent, so
+//! the numbers don't depend on the terminal running the benchmark. Results
+//! go to stderr, past the per-file lines on stdout:
+//!
+//! ```text
+//! cargo bench --bench progress >/dev/null
+//! ```
+
+mod common;
+
+use indicatif::{MultiProgress, ProgressDrawTarget, TermLike};
+use mt_downloader::Downloader;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const FILES: usize = 50;
+const FILE_SIZE: usize = 256 * 1024;
+const RUNS: usize = 3;
+
+#[derive(Debug, Default)]
+struct Counts {
+    bytes: AtomicU64,
+    flushes: AtomicU64,
+}
+
+/// A terminal that draws nothing and counts what it was asked to draw.
+#[derive(Debug, Clone, Default)]
+struct CountingTerm(Arc<Counts>);
+
+impl TermLike for CountingTerm {
+    fn width(&self) -> u16 {
+        120
+    }
+
+    fn height(&self) -> u16 {
+        60
+    }
+
+    fn move_cursor_up(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        self.0
+            .bytes
+            .fetch_add(s.len() as u64 + 1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        self.0.bytes.fetch_add(s.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.0.flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Download the batch `RUNS` times with bars ticking every `refresh`, or
+/// with none when it's `None`, and report the best time and what was drawn.
+async fn run(urls: &[url::Url], refresh: Option<Duration>) {
+    let term = CountingTerm::default();
+    let cpu_before = common::cpu_time();
+    let time = common::best_of(RUNS, || async {
+        let dir = common::scratch_dir("progress");
+        let mut builder = Downloader::builder()
+            .client(reqwest::Client::builder().no_proxy().build().unwrap())
+            .out_dir(&dir)
+            .concurrency(FILES);
+        builder = match refresh {
+            // The same rate the command line picks for --refresh-ms
+            Some(every) => {
+                let hz = (1000 / every.as_millis()).clamp(1, 20) as u8;
+                let target = ProgressDrawTarget::term_like_with_hz(Box::new(term.clone()), hz);
+                builder
+                    .progress(MultiProgress::with_draw_target(target))
+                    .refresh(every)
+            }
+            None => builder.quiet(true),
+        };
+        let results = builder.build().unwrap().download(urls).await;
+        assert!(results.iter().all(|r| r.is_ok()));
+    })
+    .await;
+    let label = match refresh {
+        Some(every) => format!("refresh {}ms", every.as_millis()),
+        None => "no progress".to_string(),
+    };
+    let cpu = match (cpu_before, common::cpu_time()) {
+        (Some(before), Some(after)) => format!("{:.1?}", (after - before) / RUNS as u32),
+        _ => "n/a".to_string(),
+    };
+    eprintln!(
+        "{label:>14}: {time:>6.1?} best of {RUNS}, {cpu:>8} CPU, {:>7} bytes drawn in {:>4} flushes",
+        term.0.bytes.load(Ordering::Relaxed) / RUNS as u64,
+        term.0.flushes.load(Ordering::Relaxed) / RUNS as u64,
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    // About a second per file, so bars live long enough to tick
+    let urls = common::serve(FILE_SIZE, 1024, Duration::from_millis(4), FILES).await;
+    eprintln!("{FILES} files of {FILE_SIZE} bytes, 1 KiB every 4 ms, all at once");
+    run(&urls, Some(Duration::from_millis(100))).await;
+    run(&urls, Some(Duration::from_millis(1000))).await;
+    run(&urls, None).await;
+}
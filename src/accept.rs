@@ -0,0 +1,77 @@
+//! `Content-Type` patterns a response has to satisfy before it's saved.
+
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use url::Url;
+
+use crate::error::{DownloadError, Result};
+
+/// Patterns like `application/*` that a type must match one of, and `!`
+/// patterns like `!text/html` that it must match none of. Matching is on the
+/// media type alone, parameters such as `charset` dropped, ignoring case.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TypeFilter {
+    accept: Vec<String>,
+    reject: Vec<String>,
+}
+
+impl TypeFilter {
+    pub(crate) fn new(patterns: &[String]) -> Self {
+        let mut filter = TypeFilter::default();
+        for p in patterns {
+            let p = p.trim().to_ascii_lowercase();
+            match p.strip_prefix('!') {
+                Some(neg) => filter.reject.push(neg.trim().to_string()),
+                None => filter.accept.push(p),
+            }
+        }
+        filter
+    }
+
+    /// Fail with [`DownloadError::UnexpectedType`] unless the response's
+    /// `Content-Type` passes. A missing header passes only when there's
+    /// nothing to match it against.
+    pub(crate) fn check(&self, url: &Url, headers: &HeaderMap) -> Result<()> {
+        if self.accept.is_empty() && self.reject.is_empty() {
+            return Ok(());
+        }
+        let raw = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let mime = raw
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let rejected = self.reject.iter().any(|p| wildcard(p, &mime));
+        let accepted = self.accept.is_empty() || self.accept.iter().any(|p| wildcard(p, &mime));
+        if rejected || !accepted {
+            return Err(DownloadError::UnexpectedType {
+                url: url.clone(),
+                content_type: (!raw.is_empty()).then(|| raw.to_string()),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of
+/// characters.
+fn wildcard(pattern: &str, text: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == text;
+    };
+    let Some(mut text) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut pieces: Vec<&str> = rest.split('*').collect();
+    let last = pieces.pop().unwrap_or_default();
+    for piece in pieces {
+        match text.find(piece) {
+            Some(i) => text = &text[i + piece.len()..],
+            None => return false,
+        }
+    }
+    text.len() >= last.len() && text.ends_with(last)
+}
@@ -0,0 +1,70 @@
+//! Auto-concurrency: resizing the download pool while a batch runs.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use crate::download::BatchProgress;
+
+/// Parallel downloads a batch starts with.
+pub(crate) const START: usize = 2;
+
+/// How often throughput is sampled and the pool resized.
+const INTERVAL: Duration = Duration::from_secs(2);
+
+/// Resize `sem`, which starts out with `START.min(max)` permits, until the
+/// task is aborted.
+///
+/// While combined throughput keeps rising by more than 5% per interval, one
+/// more download is let in, up to `max`. Any failed attempt in an interval
+/// halves the pool instead. Permits held by running downloads can't be taken
+/// back, so a shrink lands as those downloads finish.
+pub(crate) async fn run(
+    sem: Arc<Semaphore>,
+    max: usize,
+    progress: BatchProgress,
+    failures: Arc<AtomicU64>,
+) {
+    let mut limit = START.min(max);
+    // Permits still to take away from a shrink
+    let mut debt = 0;
+    let mut last_bytes = progress.bytes();
+    let mut last_failures = failures.load(Ordering::Relaxed);
+    let mut last_rate = 0.0;
+    let mut ticker = tokio::time::interval(INTERVAL);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let bytes = progress.bytes();
+        let fails = failures.load(Ordering::Relaxed);
+        let rate = (bytes - last_bytes) as f64 / INTERVAL.as_secs_f64();
+        let new_failures = fails - last_failures;
+        (last_bytes, last_failures) = (bytes, fails);
+
+        if new_failures > 0 && limit > 1 {
+            let target = (limit / 2).max(1);
+            debt += limit - target;
+            limit = target;
+            debug!(limit, new_failures, "auto-concurrency: backing off");
+        } else if new_failures == 0 && rate > last_rate * 1.05 && limit < max {
+            if debt > 0 {
+                debt -= 1;
+            } else {
+                sem.add_permits(1);
+            }
+            limit += 1;
+            debug!(
+                limit,
+                bytes_per_sec = rate as u64,
+                "auto-concurrency: growing"
+            );
+        }
+        last_rate = rate;
+        if debt > 0 {
+            debt -= sem.forget_permits(debt);
+        }
+    }
+}
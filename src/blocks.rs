@@ -0,0 +1,81 @@
+//! Per-block digests of a file, so a corrupt stretch of a large download can
+//! be found and fetched again on its own.
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::download::to_hex;
+use crate::error::{DownloadError, Result};
+
+/// The SHA-256 of every `block_size` bytes of a file, as some artifact
+/// servers publish next to large downloads.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlockHashes {
+    pub block_size: u64,
+    /// Hex digests of the blocks in order; the last block may be short.
+    pub sha256: Vec<String>,
+}
+
+impl BlockHashes {
+    pub(crate) fn count(&self) -> u64 {
+        self.sha256.len() as u64
+    }
+
+    /// Whether these describe a file of `total` bytes.
+    pub(crate) fn fits(&self, total: u64) -> bool {
+        self.block_size > 0 && total.div_ceil(self.block_size) == self.count()
+    }
+
+    /// Byte range of block `i` in a `total`-byte file, end inclusive.
+    pub(crate) fn range(&self, i: u64, total: u64) -> (u64, u64) {
+        let start = i * self.block_size;
+        (start, (start + self.block_size).min(total) - 1)
+    }
+
+    /// The first block from `first` through `last` whose bytes in the
+    /// `total`-byte file at `path` don't match, with what they hash to.
+    pub(crate) async fn first_mismatch(
+        &self,
+        path: &Path,
+        first: u64,
+        last: u64,
+        total: u64,
+    ) -> Result<Option<(u64, String)>> {
+        let read_err = || DownloadError::io(format!("read {}", path.display()));
+        let mut file = fs::File::open(path).await.map_err(read_err())?;
+        let (start, _) = self.range(first, total);
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(read_err())?;
+        let mut buf = Vec::new();
+        for i in first..=last {
+            let (start, end) = self.range(i, total);
+            buf.resize((end + 1 - start) as usize, 0);
+            file.read_exact(&mut buf).await.map_err(read_err())?;
+            let actual = to_hex(&Sha256::digest(&buf));
+            if !actual.eq_ignore_ascii_case(&self.sha256[i as usize]) {
+                return Ok(Some((i, actual)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fail with [`DownloadError::BlockMismatch`] if block `i` doesn't hash
+    /// as published.
+    pub(crate) async fn verify(&self, path: &Path, i: u64, total: u64) -> Result<()> {
+        match self.first_mismatch(path, i, i, total).await? {
+            None => Ok(()),
+            Some((block, actual)) => Err(DownloadError::BlockMismatch {
+                block,
+                expected: self.sha256[i as usize].to_ascii_lowercase(),
+                actual,
+            }),
+        }
+    }
+}
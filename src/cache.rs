@@ -0,0 +1,137 @@
+//! `ETag` and `Last-Modified` of files fetched on earlier runs, so a sync
+//! only downloads what the server says has changed.
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use url::Url;
+
+use crate::error::DownloadError;
+
+/// What a response said to identify its version of the resource.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Validators {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Make `req` conditional: a server holding the same version answers
+    /// `304 Not Modified` instead of sending it again.
+    pub(crate) fn apply(&self, mut req: RequestBuilder) -> RequestBuilder {
+        // Servers that know the ETag go by it and ignore the date
+        if let Some(etag) = &self.etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = &self.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, date);
+        }
+        req
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// Where the cached copy was saved.
+    path: PathBuf,
+    #[serde(flatten)]
+    validators: Validators,
+}
+
+/// Validators per URL, kept in a JSON file and rewritten on every change.
+///
+/// Like a [`Session`](crate::Session), the file is replaced atomically.
+#[derive(Debug)]
+pub struct HttpCache {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl HttpCache {
+    /// Load the cache kept in `path`, or start an empty one if there's no
+    /// such file yet.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let entries = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("parse cache file {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("read cache file {}", path.display())),
+        };
+        Ok(HttpCache {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// The copy of `url` saved earlier and its validators, if it's still
+    /// there.
+    pub(crate) fn get(&self, url: &Url) -> Option<(PathBuf, Validators)> {
+        let entries = self.entries.lock().unwrap();
+        let e = entries.get(url.as_str())?;
+        e.path
+            .is_file()
+            .then(|| (e.path.clone(), e.validators.clone()))
+    }
+
+    /// Remember that `url` was saved to `path` with `validators`; one the
+    /// server gave none for is forgotten, having nothing to revalidate with.
+    pub(crate) fn store(
+        &self,
+        url: &Url,
+        path: &Path,
+        validators: Validators,
+    ) -> Result<(), DownloadError> {
+        let mut entries = self.entries.lock().unwrap();
+        if validators.is_empty() {
+            if entries.remove(url.as_str()).is_none() {
+                return Ok(());
+            }
+        } else {
+            entries.insert(
+                url.to_string(),
+                Entry {
+                    path: path.to_path_buf(),
+                    validators,
+                },
+            );
+        }
+        self.save(&entries)
+    }
+
+    /// Write to a temporary file next to the cache, then rename it over.
+    fn save(&self, entries: &BTreeMap<String, Entry>) -> Result<(), DownloadError> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let json = serde_json::to_vec_pretty(entries).expect("cache entries serialize");
+        std::fs::write(&tmp, json)
+            .and_then(|()| std::fs::rename(&tmp, &self.path))
+            .map_err(DownloadError::io(format!(
+                "write cache file {}",
+                self.path.display()
+            )))
+    }
+}
@@ -0,0 +1,74 @@
+//! Keeping one copy of files that turn out to have the same content.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::fs;
+
+/// Digests of the files saved so far, each with the first path it was
+/// saved under.
+#[derive(Debug, Default)]
+pub(crate) struct Dedup {
+    seen: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl Dedup {
+    /// The file saved earlier with `digest`, if it's still there; otherwise
+    /// `path` becomes the one later duplicates are linked to.
+    pub(crate) fn original(&self, digest: &str, path: &Path) -> Option<PathBuf> {
+        let mut seen = self.seen.lock().unwrap();
+        match seen.get(digest) {
+            Some(first) if first != path && first.exists() => Some(first.clone()),
+            _ => {
+                seen.insert(digest.to_string(), path.to_path_buf());
+                None
+            }
+        }
+    }
+}
+
+/// How a duplicate ended up pointing at its original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Link {
+    Hard,
+    Symbolic,
+}
+
+/// Replace `dup` with a hard link to `original`, or a symlink where the
+/// filesystem can't do hard links (say, across devices). The new link is
+/// renamed over `dup`, so if neither works `dup` is left as it was: a copy.
+pub(crate) async fn link(original: &Path, dup: &Path) -> io::Result<Link> {
+    let mut tmp = OsString::from(dup.as_os_str());
+    tmp.push(".link");
+    let tmp = PathBuf::from(tmp);
+    let _ = fs::remove_file(&tmp).await;
+    let kind = match fs::hard_link(original, &tmp).await {
+        Ok(()) => Link::Hard,
+        Err(_) => {
+            symlink(&fs::canonicalize(original).await?, &tmp).await?;
+            Link::Symbolic
+        }
+    };
+    if let Err(e) = fs::rename(&tmp, dup).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e);
+    }
+    Ok(kind)
+}
+
+#[cfg(unix)]
+async fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    fs::symlink(target, link).await
+}
+
+#[cfg(windows)]
+async fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    fs::symlink_file(target, link).await
+}
+
+#[cfg(not(any(unix, windows)))]
+async fn symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,1229 @@
+//! A single transfer and the retry loop wrapped around it.
+
+use bytes::Bytes;
+use filetime::FileTime;
+use futures_util::{Stream, StreamExt};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressFinish, ProgressStyle};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use reqwest::{
+    header::{
+        HeaderMap, ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+        IF_MODIFIED_SINCE, LAST_MODIFIED, RANGE, USER_AGENT,
+    },
+    Method, StatusCode,
+};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::{
+    fs,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::cache::Validators;
+use crate::error::{DownloadError, Result, StatusError};
+use crate::hash::Hasher;
+use crate::monitor::BatchStats;
+use crate::naming::{
+    content_disposition_filename, extension_for_mime, release_output_path, reserve_renamed,
+};
+use crate::segmented::SegmentTiming;
+use crate::space::SpaceClaim;
+use crate::{Auth, Options, Shared};
+
+/// Delay before retrying after `attempt` failed: `base * 2^(attempt-1)`,
+/// capped by `max_backoff_ms` and optionally spread out by full jitter so
+/// simultaneous failures don't all come back at the same instant.
+fn backoff_delay(base: u64, opts: Options, attempt: u32) -> u64 {
+    let mut delay = base.saturating_mul(2u64.saturating_pow(attempt - 1));
+    if let Some(max) = opts.max_backoff_ms {
+        delay = delay.min(max);
+    }
+    if opts.jitter {
+        delay = rand::thread_rng().gen_range(0..=delay);
+    }
+    delay
+}
+
+/// Error out if `size` is over `opts.max_size`.
+pub(crate) fn check_size(size: u64, opts: Options) -> Result<()> {
+    match opts.max_size {
+        Some(limit) if size > limit => Err(DownloadError::TooLarge { limit }),
+        _ => Ok(()),
+    }
+}
+
+/// Error out if `size`, the whole file's as the server announced it, is
+/// outside the `opts.min_remote_size`..`opts.max_remote_size` range.
+pub(crate) fn check_remote_size(size: u64, opts: Options) -> Result<()> {
+    match (opts.min_remote_size, opts.max_remote_size) {
+        (Some(limit), _) if size < limit => Err(DownloadError::OutsideSizeRange {
+            size,
+            limit,
+            over: false,
+        }),
+        (_, Some(limit)) if size > limit => Err(DownloadError::OutsideSizeRange {
+            size,
+            limit,
+            over: true,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Count `n` more received bytes into the batch, and stop the download if
+/// that took the run past its total size limit and running downloads are to
+/// be cut short.
+pub(crate) fn count_received(shared: &Shared, opts: Options, n: u64) -> Result<()> {
+    let total = shared.batch.received(n);
+    match opts.max_total_size {
+        Some(limit) if opts.abort_over_total && total > limit => {
+            Err(DownloadError::OverBudget { limit })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sidecar path that holds the bytes of an in-progress download.
+pub(crate) fn part_path(path: &Path) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".part");
+    PathBuf::from(s)
+}
+
+/// Where `--save-headers` writes the response a file came in.
+fn headers_path(path: &Path) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".headers");
+    PathBuf::from(s)
+}
+
+/// The status line and headers of `resp`, one per line as on the wire.
+pub(crate) fn response_head(resp: &reqwest::Response) -> String {
+    let mut head = format!("{:?} {}\n", resp.version(), resp.status());
+    for (name, value) in resp.headers() {
+        head.push_str(&format!(
+            "{name}: {}\n",
+            String::from_utf8_lossy(value.as_bytes())
+        ));
+    }
+    head
+}
+
+/// Write `response` to the `.headers` file next to `path`.
+pub(crate) async fn save_headers(path: &Path, response: &str) -> Result<()> {
+    let sidecar = headers_path(path);
+    fs::write(&sidecar, response)
+        .await
+        .map_err(DownloadError::io(format!("write {}", sidecar.display())))
+}
+
+/// Start offset of a `Content-Range: bytes <start>-<end>/<total>` header.
+fn content_range_start(resp: &reqwest::Response) -> Option<u64> {
+    let value = resp.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.strip_prefix("bytes ")?;
+    range.split('-').next()?.trim().parse().ok()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Start a request with the batch-wide settings (credentials) applied.
+///
+/// Explicit credentials replace any `user:pass@` embedded in the URL, which
+/// reqwest would otherwise send as Basic auth; a netrc entry for the host
+/// only applies when neither is there.
+pub(crate) fn request(shared: &Shared, method: Method, url: &Url) -> reqwest::RequestBuilder {
+    #[cfg(feature = "s3")]
+    let url = &*crate::s3::resolve(url, shared.aws.as_deref());
+    debug!(%method, %url, "request");
+    let mut req = shared.client.request(method.clone(), url.clone());
+    // Ahead of the task's own headers, which may name one of their own
+    if let Some(agent) = shared.user_agents.choose(&mut rand::thread_rng()) {
+        req = req.header(USER_AGENT, agent.clone());
+    }
+    if let Some(headers) = &shared.headers {
+        req = req.headers(HeaderMap::clone(headers));
+    }
+    if let Some(timeout) = shared.timeout {
+        req = req.timeout(timeout);
+    }
+    match &shared.auth {
+        Some(Auth::Basic { user, password }) => req.basic_auth(user, password.as_deref()),
+        Some(Auth::Bearer(token)) => req.bearer_auth(token),
+        None => match netrc_login(shared, url) {
+            Some((user, password)) => req.basic_auth(user, password),
+            #[cfg(feature = "s3")]
+            None => match &shared.aws {
+                Some(creds) => crate::s3::sign(req, &method, url, creds),
+                None => req,
+            },
+            #[cfg(not(feature = "s3"))]
+            None => req,
+        },
+    }
+}
+
+/// The netrc login for `url`'s host, unless the URL names a user itself.
+fn netrc_login<'a>(shared: &'a Shared, url: &Url) -> Option<(&'a str, Option<&'a str>)> {
+    if !url.username().is_empty() {
+        return None;
+    }
+    shared.netrc.as_deref()?.credentials(url.host_str()?)
+}
+
+/// Whether the file at `path` already matches the remote one, judged by a
+/// `HEAD` request carrying `If-Modified-Since`.
+///
+/// Anything short of a clear answer (no local file, request errors, missing
+/// `Last-Modified`) counts as stale so the normal download path takes over.
+pub(crate) async fn is_up_to_date(shared: &Shared, url: &Url, path: &Path) -> bool {
+    let Ok(meta) = fs::metadata(path).await else {
+        return false;
+    };
+    let Ok(local_mtime) = meta.modified() else {
+        return false;
+    };
+    let Ok(resp) = request(shared, Method::HEAD, url)
+        .header(IF_MODIFIED_SINCE, httpdate::fmt_http_date(local_mtime))
+        .send()
+        .await
+    else {
+        return false;
+    };
+
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return true;
+    }
+    if !resp.status().is_success() {
+        debug!(%url, status = %resp.status(), "timestamp check failed");
+        return false;
+    }
+    let headers = resp.headers();
+    let remote_mtime = last_modified(headers);
+    let remote_len = header_content_length(headers);
+    match remote_mtime {
+        Some(t) => t <= local_mtime && remote_len.is_none_or(|len| len == meta.len()),
+        None => false,
+    }
+}
+
+/// Whether the file at `path` is already complete, judged by its size against
+/// the `Content-Length` of a `HEAD` request.
+///
+/// A shorter file is moved to the `.part` path, unless one is already there,
+/// so the download resumes from it. Without a clear answer the file counts as
+/// incomplete and is downloaded again.
+pub(crate) async fn is_complete(shared: &Shared, url: &Url, path: &Path, opts: Options) -> bool {
+    let Ok(meta) = fs::metadata(path).await else {
+        return false;
+    };
+    let Ok(resp) = request(shared, Method::HEAD, url).send().await else {
+        return false;
+    };
+    if !resp.status().is_success() {
+        debug!(%url, status = %resp.status(), "size check failed");
+        return false;
+    }
+    let Some(remote_len) = header_content_length(resp.headers()) else {
+        return false;
+    };
+    if meta.len() == remote_len {
+        return true;
+    }
+    let part = part_path(path);
+    if meta.len() < remote_len && !opts.compressed && fs::metadata(&part).await.is_err() {
+        debug!(%url, have = meta.len(), remote_len, "resuming existing file");
+        let _ = fs::rename(path, &part).await;
+    }
+    false
+}
+
+/// What a successful attempt left on disk.
+pub(crate) struct Saved {
+    pub(crate) path: PathBuf,
+    /// Bytes received by the attempt that finished the file.
+    pub(crate) bytes: u64,
+    /// Where the body actually came from, after any redirects.
+    pub(crate) final_url: Url,
+    /// Hex digest of the file, when `opts.digest` asked for one.
+    pub(crate) digest: Option<String>,
+    /// `Content-Type` the body was served with.
+    pub(crate) content_type: Option<String>,
+    /// From sending the request to its response headers; `None` for local
+    /// files.
+    pub(crate) ttfb: Option<Duration>,
+    /// How long the attempt that finished the file took, start to end.
+    pub(crate) elapsed: Duration,
+    /// `ETag` and `Last-Modified` the file was served with, for the cache.
+    pub(crate) validators: Validators,
+    /// Status line and headers it was served with, when `opts.save_headers`
+    /// asked for them.
+    pub(crate) response: Option<String>,
+    /// How each range went, for a segmented download.
+    pub(crate) segments: Vec<SegmentTiming>,
+}
+
+/// `Last-Modified` as a timestamp, if present and well-formed.
+pub(crate) fn last_modified(headers: &HeaderMap) -> Option<SystemTime> {
+    headers
+        .get(LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+}
+
+/// The advertised `Content-Length`. HEAD responses have no body, so
+/// `Response::content_length` can't be trusted for them.
+pub(crate) fn header_content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Whether the server advertises byte ranges with `Accept-Ranges: bytes`.
+pub(crate) fn accepts_ranges(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT_RANGES)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("bytes"))
+}
+
+/// Final location for a download planned at `path`: the server's suggested
+/// name wins over the one we guessed from the URL.
+pub(crate) fn destination(
+    shared: &Shared,
+    headers: &HeaderMap,
+    path: &Path,
+    opts: Options,
+) -> PathBuf {
+    if opts.fixed_name {
+        return path.to_path_buf();
+    }
+    let current = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or_default();
+    let mut name = headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(content_disposition_filename)
+        .unwrap_or_else(|| current.to_string());
+
+    if opts.adjust_extension && Path::new(&name).extension().is_none() {
+        if let Some(ext) = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(extension_for_mime)
+        {
+            name = format!("{name}.{ext}");
+        }
+    }
+
+    if name == current {
+        return path.to_path_buf();
+    }
+    reserve_renamed(
+        &shared.reserved,
+        path,
+        &name,
+        opts.on_conflict.keeps_name(),
+        opts.number_by,
+    )
+}
+
+/// Style from an indicatif `template`, with its color and attribute
+/// directives (`{bar:40.cyan/blue}` -> `{bar:40}`) dropped unless `color`.
+fn bar_style(template: &str, color: bool) -> ProgressStyle {
+    let template = if color {
+        template.to_string()
+    } else {
+        let mut plain = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            let close = open + rest[open..].find('}').expect("placeholders are closed");
+            plain.push_str(&rest[..open]);
+            let (key, spec) = rest[open + 1..close]
+                .split_once(':')
+                .unwrap_or((&rest[open + 1..close], ""));
+            let width: String = spec.chars().take_while(char::is_ascii_digit).collect();
+            if width.is_empty() {
+                plain.push_str(&format!("{{{key}}}"));
+            } else {
+                plain.push_str(&format!("{{{key}:{width}}}"));
+            }
+            rest = &rest[close + 1..];
+        }
+        plain.push_str(rest);
+        plain
+    };
+    ProgressStyle::with_template(&template)
+        .expect("progress templates are valid")
+        .progress_chars("##-")
+}
+
+/// A progress bar for `dest`, registered with the batch's `MultiProgress`;
+/// `total` is `None` when the size isn't known up front.
+pub(crate) fn progress_bar(
+    shared: &Shared,
+    dest: &Path,
+    total: Option<u64>,
+    opts: Options,
+) -> ProgressBar {
+    // indicatif's rate and ETA are exponentially weighted averages, so they
+    // don't jump around with every chunk
+    let (pb, template) = match total {
+        Some(n) if n > 0 => (
+            ProgressBar::new(n),
+            "{prefix:.cyan.bold} [{bar:40.cyan/blue}] {bytes}/{total_bytes} \
+             {bytes_per_sec} eta {eta} ({elapsed})",
+        ),
+        // Without a size a bar would sit at 0% the whole time
+        _ => (
+            ProgressBar::no_length(),
+            "{prefix:.cyan.bold} {spinner} {bytes} {bytes_per_sec} ({elapsed})",
+        ),
+    };
+    // Bars of failed attempts vanish on drop instead of piling up across retries
+    let pb = shared.multi.add(pb.with_finish(ProgressFinish::AndClear));
+    let prefix = dest
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "download".to_string());
+    pb.set_prefix(prefix);
+    pb.set_style(bar_style(template, shared.color));
+    // Each ticking bar costs a thread; nobody sees a hidden one tick
+    if !shared.multi.is_hidden() {
+        pb.enable_steady_tick(opts.refresh);
+    }
+    if let Some(t) = &shared.tracker {
+        t.attach(&pb);
+    }
+    pb
+}
+
+/// The bar above the per-file ones: files finished out of the batch, plus
+/// bytes received across all of them and the overall rate. Hidden for
+/// single-file batches and in quiet mode.
+#[derive(Debug, Clone)]
+pub(crate) struct BatchProgress {
+    bar: ProgressBar,
+    stats: Arc<BatchStats>,
+    /// What `stats` held before this batch, left out of its rate.
+    base: u64,
+}
+
+impl BatchProgress {
+    /// Counts into `stats` without drawing anything.
+    pub(crate) fn hidden(stats: Arc<BatchStats>) -> Self {
+        BatchProgress {
+            bar: ProgressBar::hidden(),
+            base: stats.bytes(),
+            stats,
+        }
+    }
+
+    /// A bar for `files` downloads, pinned to the top of `multi`, also
+    /// counted into `stats`.
+    pub(crate) fn new(
+        multi: &MultiProgress,
+        files: u64,
+        color: bool,
+        stats: Arc<BatchStats>,
+    ) -> Self {
+        let bar = multi.insert(0, ProgressBar::new(files));
+        bar.set_style(bar_style(
+            "{prefix:.bold} [{bar:40.green/white}] {pos}/{len} files {msg} \
+             eta {eta} ({elapsed})",
+            color,
+        ));
+        bar.set_prefix("total");
+        bar.enable_steady_tick(Duration::from_millis(500));
+        BatchProgress {
+            bar,
+            base: stats.bytes(),
+            stats,
+        }
+    }
+
+    /// `n` more bytes arrived for some file in the batch. Returns the total
+    /// for the run, earlier batches included.
+    pub(crate) fn received(&self, n: u64) -> u64 {
+        let run = self.stats.received(n);
+        let total = run - self.base;
+        if self.bar.is_hidden() {
+            return run;
+        }
+        let rate = total as f64 / self.bar.elapsed().as_secs_f64().max(0.001);
+        self.bar.set_message(format!(
+            "{} at {}/s",
+            HumanBytes(total),
+            HumanBytes(rate as u64)
+        ));
+        run
+    }
+
+    /// Bytes received across the batch so far.
+    pub(crate) fn bytes(&self) -> u64 {
+        self.stats.bytes() - self.base
+    }
+
+    /// One more download is over, however it ended.
+    pub(crate) fn file_done(&self) {
+        self.stats.file_done();
+        self.bar.inc(1);
+    }
+
+    pub(crate) fn finish(&self, clear: bool) {
+        if clear {
+            self.bar.finish_and_clear();
+        } else {
+            self.bar.finish();
+        }
+    }
+}
+
+/// Next chunk of a response body, failing if `--read-timeout` elapses first.
+pub(crate) async fn next_chunk<S>(
+    stream: &mut S,
+    opts: Options,
+    url: &Url,
+    at: u64,
+) -> Result<Option<Bytes>>
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
+{
+    let next = match opts.read_timeout {
+        Some(idle) => tokio::time::timeout(idle, stream.next())
+            .await
+            .map_err(|_| DownloadError::Timeout {
+                url: url.clone(),
+                idle,
+            })?,
+        None => stream.next().await,
+    };
+    next.transpose()
+        .map_err(|source| DownloadError::StreamBroken {
+            url: url.clone(),
+            at,
+            source,
+        })
+}
+
+/// `min_rate` for one attempt: the average speed over each stretch of
+/// `min_rate_time` has to reach the minimum, or the attempt fails.
+pub(crate) struct MinRate {
+    min: u64,
+    grace: Duration,
+    since: Instant,
+    bytes: u64,
+}
+
+impl MinRate {
+    /// Checks for one `share`th of a file, such as a segment; `None` when
+    /// there's no minimum.
+    pub(crate) fn new(opts: Options, share: u64) -> Option<Self> {
+        Some(MinRate {
+            min: opts.min_rate? / share.max(1),
+            grace: opts.min_rate_time,
+            since: Instant::now(),
+            bytes: 0,
+        })
+    }
+
+    /// Count `n` more bytes, failing if that ends a stretch that was too slow.
+    pub(crate) fn add(&mut self, n: u64, url: &Url) -> Result<()> {
+        self.bytes += n;
+        let over = self.since.elapsed();
+        if over < self.grace {
+            return Ok(());
+        }
+        let rate = (self.bytes as f64 / over.as_secs_f64()) as u64;
+        if rate < self.min {
+            return Err(DownloadError::TooSlow {
+                url: url.clone(),
+                rate,
+                min: self.min,
+                over,
+            });
+        }
+        (self.since, self.bytes) = (Instant::now(), 0);
+        Ok(())
+    }
+}
+
+/// Resolves once `pace`'s current stretch is over, so a stream that sends
+/// nothing at all still gets judged; never, without a minimum.
+pub(crate) async fn stretch_over(pace: &Option<MinRate>) {
+    match pace {
+        Some(p) => tokio::time::sleep_until((p.since + p.grace).into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Compare a finished digest with the expected one, discarding the `.part`
+/// file on mismatch so the next attempt starts from scratch.
+pub(crate) async fn verify_sha256(
+    hasher: Sha256,
+    expected: &str,
+    part: &Path,
+    pb: &ProgressBar,
+) -> Result<()> {
+    let actual = to_hex(&hasher.finalize());
+    if actual.eq_ignore_ascii_case(expected) {
+        return Ok(());
+    }
+    pb.abandon_with_message("checksum mismatch");
+    let _ = fs::remove_file(part).await;
+    Err(DownloadError::ChecksumMismatch {
+        expected: expected.to_string(),
+        actual,
+    })
+}
+
+/// Move a completed `.part` file into place and apply the remote mtime.
+pub(crate) async fn finish(
+    part: &Path,
+    dest: &Path,
+    mtime: Option<SystemTime>,
+    opts: Options,
+    pb: &ProgressBar,
+) -> Result<()> {
+    fs::rename(part, dest)
+        .await
+        .map_err(DownloadError::io(format!(
+            "rename {} -> {}",
+            part.display(),
+            dest.display()
+        )))?;
+    if let (true, Some(mtime)) = (opts.timestamping, mtime) {
+        filetime::set_file_mtime(dest, FileTime::from_system_time(mtime)).map_err(
+            DownloadError::io(format!("set modification time of {}", dest.display())),
+        )?;
+    }
+    if opts.clear_finished {
+        pb.finish_and_clear();
+    } else {
+        pb.finish_with_message("done");
+    }
+    Ok(())
+}
+
+pub(crate) async fn download_once(
+    shared: &Shared,
+    url: &Url,
+    path: &Path,
+    opts: Options,
+    expected_sha256: Option<&str>,
+) -> Result<Saved> {
+    // Pick up where a previous attempt left off, if it left anything behind
+    let part = part_path(path);
+    // Offsets into a compressed stream don't match the decoded bytes on disk,
+    // and a range of some other request's response means nothing
+    let existing = match fs::metadata(&part).await {
+        Ok(meta) if !opts.compressed && shared.method == Method::GET => meta.len(),
+        _ => 0,
+    };
+
+    let mut req = request(shared, shared.method.clone(), url);
+    if let Some(body) = &shared.body {
+        req = req.body(body.clone());
+    }
+    if existing > 0 {
+        req = req.header(RANGE, format!("bytes={existing}-"));
+    } else if let Some(cached) = &shared.validators {
+        req = cached.apply(req);
+    }
+    let sent = Instant::now();
+    let resp = tokio::select! {
+        _ = shared.cancel.cancelled() => return Err(DownloadError::Interrupted),
+        resp = req.send() => resp.map_err(DownloadError::network(url))?,
+    };
+    let ttfb = sent.elapsed();
+    debug!(%url, status = %resp.status(), headers = ?resp.headers(), ?ttfb, "response");
+
+    if resp.status() == StatusCode::NOT_MODIFIED && shared.validators.is_some() {
+        return Err(DownloadError::NotModified);
+    }
+    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // Our partial file doesn't line up with the remote one; start over next time
+        let _ = fs::remove_file(&part).await;
+        return Err(DownloadError::Protocol(format!(
+            "server rejected resume at byte {existing} for {url}"
+        )));
+    }
+    if !resp.status().is_success() {
+        return Err(StatusError::from_response(&resp).into());
+    }
+    shared.accept.check(url, resp.headers())?;
+
+    // 206 continues the partial file; a plain 200 means ranges aren't supported
+    let resumed = existing > 0 && resp.status() == StatusCode::PARTIAL_CONTENT;
+    if resumed && content_range_start(&resp) != Some(existing) {
+        let _ = fs::remove_file(&part).await;
+        return Err(DownloadError::Protocol(format!(
+            "server resumed at the wrong offset for {url}"
+        )));
+    }
+    let start = if resumed { existing } else { 0 };
+    if let Some(len) = resp.content_length() {
+        let checked =
+            check_remote_size(start + len, opts).and_then(|()| check_size(start + len, opts));
+        if let Err(e) = checked {
+            let _ = fs::remove_file(&part).await;
+            return Err(e);
+        }
+    }
+    // Fail now rather than on ENOSPC halfway through
+    let mut claim = match resp.content_length() {
+        Some(len) => Some(SpaceClaim::new(shared, &part, len)?),
+        None => None,
+    };
+
+    let final_url = resp.url().clone();
+    let content_type = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let content_length = resp.content_length();
+    let mtime = last_modified(resp.headers());
+    let validators = Validators::from_headers(resp.headers());
+    let response = opts.save_headers.then(|| response_head(&resp));
+    let dest = destination(shared, resp.headers(), path, opts);
+    debug!(%url, dest = %dest.display(), resume_from = start, "saving");
+
+    let res = async {
+        // Bytes already on disk never pass through the stream, so hash them up front
+        let mut hasher = expected_sha256.map(|_| Sha256::new());
+        let mut digest = opts.digest.map(Hasher::new);
+        if resumed && (hasher.is_some() || digest.is_some()) {
+            let prefix = fs::read(&part).await.map_err(DownloadError::io(format!(
+                "read partial file {}",
+                part.display()
+            )))?;
+            if let Some(h) = hasher.as_mut() {
+                h.update(&prefix);
+            }
+            if let Some(d) = digest.as_mut() {
+                d.update(&prefix);
+            }
+        }
+
+        // Decoded bodies have no known length
+        let pb = progress_bar(
+            shared,
+            &dest,
+            resp.content_length().map(|n| n + start),
+            opts,
+        );
+        pb.set_position(start);
+
+        // Stream response into the .part file, then move it into place
+        let file = if resumed {
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&part)
+                .await
+                .map_err(DownloadError::io(format!("open file {}", part.display())))?
+        } else {
+            fs::File::create(&part)
+                .await
+                .map_err(DownloadError::io(format!("create file {}", part.display())))?
+        };
+        let mut file = BufWriter::with_capacity(opts.buffer_size, file);
+        let streamed = stream_body(shared, url, opts, resp, start, &mut file, &part, |chunk| {
+            if let Some(h) = hasher.as_mut() {
+                h.update(chunk);
+            }
+            if let Some(d) = digest.as_mut() {
+                d.update(chunk);
+            }
+            if let Some(c) = claim.as_mut() {
+                c.wrote(chunk.len() as u64);
+            }
+            pb.inc(chunk.len() as u64);
+        })
+        .await;
+        drop(file);
+        let received = match streamed {
+            Ok(received) => received,
+            Err(e @ DownloadError::TooLarge { .. }) => {
+                let _ = fs::remove_file(&part).await;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
+        // A connection that closes early ends the stream just like a finished one
+        if let Some(expected) = content_length {
+            if received != expected {
+                // A short file resumes on the next attempt; a long one is garbage
+                if received > expected {
+                    let _ = fs::remove_file(&part).await;
+                }
+                pb.abandon_with_message("incomplete");
+                return Err(DownloadError::LengthMismatch {
+                    url: url.clone(),
+                    expected,
+                    received,
+                });
+            }
+        }
+
+        if let (Some(h), Some(expected)) = (hasher, expected_sha256) {
+            verify_sha256(h, expected, &part, &pb).await?;
+        }
+        finish(&part, &dest, mtime, opts, &pb).await?;
+
+        Ok(Saved {
+            path: dest.clone(),
+            bytes: received,
+            final_url,
+            digest: digest.map(Hasher::finalize_hex),
+            content_type,
+            ttfb: Some(ttfb),
+            elapsed: sent.elapsed(),
+            validators,
+            response,
+            segments: Vec::new(),
+        })
+    }
+    .await;
+
+    if res.is_err() {
+        // The next attempt picks its name afresh; holding on to this one would
+        // push it to `name (1)`
+        if dest != path {
+            release_output_path(&shared.reserved, &dest);
+        }
+        // A decoded body can't be resumed, so what it left behind is garbage
+        if opts.compressed && !opts.keep_partial {
+            let _ = fs::remove_file(&part).await;
+        }
+    }
+    res
+}
+
+/// Copy a response body into `sink`, a file, a buffer or anything else that
+/// takes bytes, handing each chunk to `seen` as well. `start` is how much
+/// of the file came before it, and `dest` names the sink in write errors.
+///
+/// Size limits, the minimum rate, rate limits and cancellation all apply on
+/// the way. On failure what arrived is flushed into `sink`, for a resume,
+/// unless the failure is [`DownloadError::TooLarge`]. Returns the number of
+/// bytes received.
+#[allow(clippy::too_many_arguments)]
+async fn stream_body<W: AsyncWrite + Unpin>(
+    shared: &Shared,
+    url: &Url,
+    opts: Options,
+    resp: reqwest::Response,
+    start: u64,
+    sink: &mut W,
+    dest: &Path,
+    mut seen: impl FnMut(&[u8]),
+) -> Result<u64> {
+    let write_err = || DownloadError::io(format!("write {}", dest.display()));
+    let mut received = 0u64;
+    let mut stream = resp.bytes_stream();
+    let mut pace = MinRate::new(opts, 1);
+    loop {
+        let next = tokio::select! {
+            biased;
+            _ = shared.cancel.cancelled() => Err(DownloadError::Interrupted),
+            chunk = next_chunk(&mut stream, opts, url, start + received) => chunk,
+            // Nothing came for a whole stretch; judge it all the same
+            () = stretch_over(&pace) => Ok(Some(Bytes::new())),
+        };
+        let next = next.and_then(|chunk| match (&mut pace, &chunk) {
+            (Some(p), Some(c)) => p.add(c.len() as u64, url).map(|()| chunk),
+            _ => Ok(chunk),
+        });
+        let chunk = match next {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                // Make sure the next attempt resumes from every byte we got
+                sink.flush().await.map_err(write_err())?;
+                return Err(e);
+            }
+        };
+        // Content-Length can be missing or wrong, so keep counting
+        check_size(start + received + chunk.len() as u64, opts)?;
+        throttle(shared, chunk.len() as u64).await;
+        sink.write_all(&chunk).await.map_err(write_err())?;
+        seen(&chunk);
+        received += chunk.len() as u64;
+        if let Err(e) = count_received(shared, opts, chunk.len() as u64) {
+            // Kept for a later run with room to finish it
+            sink.flush().await.map_err(write_err())?;
+            return Err(e);
+        }
+    }
+    sink.flush().await.map_err(write_err())?;
+    Ok(received)
+}
+
+/// Where a download would land and what the server said about it, for a dry
+/// run.
+pub(crate) struct Probed {
+    pub(crate) path: PathBuf,
+    pub(crate) size: Option<u64>,
+    pub(crate) final_url: Url,
+    pub(crate) content_type: Option<String>,
+    pub(crate) last_modified: Option<SystemTime>,
+    /// `None` for local files, where it doesn't apply.
+    pub(crate) accept_ranges: Option<bool>,
+}
+
+/// Ask the server about `url` without fetching the body: a `HEAD`, or a `GET`
+/// dropped after the headers when `HEAD` isn't allowed.
+pub(crate) async fn probe(
+    shared: &Shared,
+    url: &Url,
+    path: &Path,
+    opts: Options,
+) -> Result<Probed> {
+    let send = |method| async move {
+        let req = request(shared, method, url);
+        tokio::select! {
+            _ = shared.cancel.cancelled() => Err(DownloadError::Interrupted),
+            resp = req.send() => resp.map_err(DownloadError::network(url)),
+        }
+    };
+    let mut resp = send(Method::HEAD).await?;
+    if matches!(
+        resp.status(),
+        StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+    ) {
+        resp = send(Method::GET).await?;
+    }
+    debug!(%url, status = %resp.status(), headers = ?resp.headers(), "probe");
+    if !resp.status().is_success() {
+        return Err(StatusError::from_response(&resp).into());
+    }
+    shared.accept.check(url, resp.headers())?;
+    let size = header_content_length(resp.headers());
+    if let Some(size) = size {
+        check_remote_size(size, opts)?;
+        check_size(size, opts)?;
+    }
+    let headers = resp.headers();
+    Ok(Probed {
+        path: destination(shared, headers, path, opts),
+        size,
+        final_url: resp.url().clone(),
+        content_type: headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        last_modified: last_modified(headers),
+        accept_ranges: Some(accepts_ranges(headers)),
+    })
+}
+
+/// Wait until `bytes` fit under the file's own cap and then the global one.
+pub(crate) async fn throttle(shared: &Shared, bytes: u64) {
+    if let Some(limiter) = &shared.file_limiter {
+        limiter.acquire(bytes).await;
+    }
+    if let Some(limiter) = &shared.limiter {
+        limiter.acquire(bytes).await;
+    }
+}
+
+/// Run `op` until it succeeds, fails with a non-retryable error, or runs out
+/// of attempts or of `max_retry_time`, sleeping between tries per the backoff
+/// settings (or the server's `Retry-After`). With `connect_retries` set,
+/// failures to connect use up those and their backoff instead. Returns the
+/// outcome and the attempts made, of both kinds.
+pub(crate) async fn with_retries<T, F, Fut>(
+    shared: &Shared,
+    url: &Url,
+    opts: Options,
+    mut op: F,
+) -> (Result<T>, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    // `retries` counts every attempt, the first one included
+    let max_attempts = opts.retries.max(1);
+    let mut attempts = 0;
+    // Failures of each kind so far, when connecting has its own count
+    let (mut failed, mut failed_connect) = (0, 0);
+    let started = Instant::now();
+
+    let err = loop {
+        attempts += 1;
+        let e = match op().await {
+            Ok(v) => return (Ok(v), attempts),
+            Err(e) => e,
+        };
+        let connect = opts.connect_retries.is_some() && e.is_connect();
+        let (attempt, max_attempts, base) = match opts.connect_retries {
+            Some(max) if connect => {
+                failed_connect += 1;
+                let base = opts.connect_backoff_ms.unwrap_or(opts.backoff_ms);
+                (failed_connect, max.max(1), base)
+            }
+            _ => {
+                failed += 1;
+                (failed, max_attempts, opts.backoff_ms)
+            }
+        };
+        if !matches!(
+            e,
+            DownloadError::Interrupted
+                | DownloadError::OverBudget { .. }
+                | DownloadError::NotModified
+                | DownloadError::OutsideSizeRange { .. }
+        ) {
+            shared.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        if !e.is_retryable() {
+            debug!(%url, attempt, error = %e, "not retryable");
+            break e;
+        }
+        if attempt == max_attempts {
+            debug!(%url, attempt, error = %e, "out of attempts");
+            break e;
+        }
+        // A rate-limited server knows better than our backoff schedule
+        let retry_after = match &e {
+            DownloadError::HttpStatus(s) => s.retry_after,
+            _ => None,
+        };
+        let delay = retry_after
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_else(|| backoff_delay(base, opts, attempt));
+        // Give up early rather than sleep past the time budget
+        if let Some(budget) = opts.max_retry_time {
+            if started.elapsed() + Duration::from_millis(delay) > budget {
+                debug!(%url, attempt, ?budget, error = %e, "retry time exhausted");
+                break e;
+            }
+        }
+        info!(%url, attempt, delay_ms = delay, error = e.chain(), "retrying");
+        if let Some(t) = &shared.tracker {
+            t.retry(url, attempt, max_attempts, &e);
+        }
+        if !opts.quiet {
+            let resume = match &e {
+                DownloadError::StreamBroken { at, .. } if !opts.compressed => {
+                    format!(", resuming at byte {at}")
+                }
+                _ => String::new(),
+            };
+            let what = if connect {
+                "connection attempt"
+            } else {
+                "attempt"
+            };
+            shared.multi.suspend(|| {
+                println!(
+                    "{what} {attempt}/{max_attempts} for {url} failed; retrying in {delay}ms{resume}"
+                )
+            });
+        }
+        tokio::select! {
+            _ = shared.cancel.cancelled() => break DownloadError::Interrupted,
+            _ = tokio::time::sleep(Duration::from_millis(delay)) => {}
+        }
+    };
+    (Err(err), attempts)
+}
+
+pub(crate) async fn download_with_retries(
+    shared: &Shared,
+    url: &Url,
+    path: &Path,
+    opts: Options,
+    expected_sha256: Option<&str>,
+) -> (Result<Saved>, u32) {
+    let (res, attempts) = with_retries(shared, url, opts, || {
+        download_once(shared, url, path, opts, expected_sha256)
+    })
+    .await;
+
+    // Retries resume from the .part file; once we give up, drop it unless asked
+    // not to. An interrupted or aborted download always keeps it.
+    let interrupted = matches!(
+        &res,
+        Err(DownloadError::Interrupted | DownloadError::OverBudget { .. })
+    );
+    if res.is_err() && !opts.keep_partial && !interrupted {
+        let _ = fs::remove_file(part_path(path)).await;
+    }
+
+    (res, attempts)
+}
+
+/// One attempt at `url`'s body, kept in memory instead of written to a file.
+/// Each attempt starts over; there's no `.part` file to resume from.
+pub(crate) async fn fetch_bytes(shared: &Shared, url: &Url, opts: Options) -> Result<Vec<u8>> {
+    let mut req = request(shared, shared.method.clone(), url);
+    if let Some(body) = &shared.body {
+        req = req.body(body.clone());
+    }
+    let resp = tokio::select! {
+        _ = shared.cancel.cancelled() => return Err(DownloadError::Interrupted),
+        resp = req.send() => resp.map_err(DownloadError::network(url))?,
+    };
+    debug!(%url, status = %resp.status(), headers = ?resp.headers(), "response");
+    if !resp.status().is_success() {
+        return Err(StatusError::from_response(&resp).into());
+    }
+    shared.accept.check(url, resp.headers())?;
+    let content_length = resp.content_length();
+    if let Some(len) = content_length {
+        check_size(len, opts)?;
+    }
+
+    // Only what the server announced, and not all of it when that's a lot
+    let mut body = Vec::with_capacity(content_length.unwrap_or(0).min(1 << 24) as usize);
+    let memory = Path::new("response body in memory");
+    stream_body(shared, url, opts, resp, 0, &mut body, memory, |_| {}).await?;
+
+    if let Some(expected) = content_length.filter(|&n| n != body.len() as u64) {
+        return Err(DownloadError::LengthMismatch {
+            url: url.clone(),
+            expected,
+            received: body.len() as u64,
+        });
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Downloader;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// A server that answers every request with `head` and then `body`, and
+    /// hangs up.
+    async fn serve(head: &'static str, body: &'static [u8]) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                let mut buf = [0; 4096];
+                let _ = conn.read(&mut buf).await;
+                let _ = conn.write_all(head.as_bytes()).await;
+                let _ = conn.write_all(body).await;
+                let _ = conn.shutdown().await;
+            }
+        });
+        Url::parse(&format!("http://{addr}/file.bin")).unwrap()
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mt-downloader-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn downloader(retries: u32) -> Downloader {
+        Downloader::builder()
+            .client(reqwest::Client::builder().no_proxy().build().unwrap())
+            .retries(retries)
+            .backoff_ms(0)
+            .quiet(true)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_counts_every_attempt() {
+        let url = Url::parse("http://example.invalid/").unwrap();
+        for retries in 1..=3 {
+            let d = downloader(retries);
+            let mut calls = 0;
+            let (res, attempts) = with_retries(&d.shared, &url, d.opts, || {
+                calls += 1;
+                async { Err::<(), _>(DownloadError::Protocol("flaky".into())) }
+            })
+            .await;
+            assert!(res.is_err());
+            assert_eq!(calls, retries);
+            assert_eq!(attempts, retries);
+        }
+    }
+
+    #[tokio::test]
+    async fn zero_retries_still_tries_once() {
+        let url = Url::parse("http://example.invalid/").unwrap();
+        let d = downloader(0);
+        let mut calls = 0;
+        let (_, attempts) = with_retries(&d.shared, &url, d.opts, || {
+            calls += 1;
+            async { Err::<(), _>(DownloadError::Protocol("flaky".into())) }
+        })
+        .await;
+        assert_eq!((calls, attempts), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn failed_download_leaves_no_file_behind() {
+        let url = serve("HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n", b"short").await;
+        let dir = scratch_dir("cleanup");
+        let path = dir.join("file.bin");
+        let d = downloader(2);
+
+        let (res, attempts) = download_with_retries(&d.shared, &url, &path, d.opts, None).await;
+        assert!(res.is_err());
+        assert_eq!(attempts, 2);
+        assert!(!path.exists());
+        assert!(!part_path(&path).exists());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn failed_decoded_attempt_leaves_no_part_file() {
+        let url = serve("HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n", b"short").await;
+        let dir = scratch_dir("cleanup-decoded");
+        let path = dir.join("file.bin");
+        let d = downloader(1);
+        let opts = Options {
+            compressed: true,
+            ..d.opts
+        };
+
+        // A decoded body can't be resumed, so not even a retry keeps it
+        assert!(download_once(&d.shared, &url, &path, opts, None)
+            .await
+            .is_err());
+        assert!(!path.exists());
+        assert!(!part_path(&path).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn download_to_bytes_keeps_to_the_per_file_cap() {
+        static BODY: [u8; 40_000] = [b'x'; 40_000];
+        let url = serve("HTTP/1.1 200 OK\r\nContent-Length: 40000\r\n\r\n", &BODY).await;
+        let d = Downloader::builder()
+            .client(reqwest::Client::builder().no_proxy().build().unwrap())
+            .max_rate_per_file(Some(20_000))
+            .quiet(true)
+            .build()
+            .unwrap();
+
+        // The first second's worth comes at once, the rest at the cap
+        let started = Instant::now();
+        assert_eq!(d.download_to_bytes(&url).await.unwrap(), BODY);
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+}
@@ -0,0 +1,337 @@
+//! What can go wrong with one download.
+
+use reqwest::{
+    header::{LOCATION, RETRY_AFTER},
+    StatusCode,
+};
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
+
+/// Shorthand for results that fail with a [`DownloadError`].
+pub type Result<T, E = DownloadError> = std::result::Result<T, E>;
+
+/// Why a download failed.
+///
+/// [`is_retryable`](Self::is_retryable) is what the retry loop goes by.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum DownloadError {
+    /// The request couldn't be sent or no response came back.
+    #[error("request failed: {url}: {source}")]
+    Network {
+        url: Url,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    /// The connection broke after `at` bytes of the body were in. Those stay
+    /// in the `.part` file (unless the body was being decompressed), so the
+    /// next attempt asks for the rest with a `Range` request.
+    #[error("connection lost at byte {at} of {url}: {source}")]
+    StreamBroken {
+        url: Url,
+        at: u64,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    /// The server answered with a non-2xx status.
+    #[error(transparent)]
+    HttpStatus(Box<StatusError>),
+
+    /// No data arrived for longer than the read timeout.
+    #[error("no data received for {idle:?} from {url}")]
+    Timeout { url: Url, idle: Duration },
+
+    /// Bytes kept arriving, but for a whole grace period of `over` they came
+    /// slower than the minimum rate.
+    #[error("{url} slowed to {rate} B/s for {}s, under the {min} B/s minimum", over.as_secs())]
+    TooSlow {
+        url: Url,
+        rate: u64,
+        min: u64,
+        over: Duration,
+    },
+
+    /// Reading or writing a local file failed.
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The file didn't hash to the expected SHA-256.
+    #[error("sha256 mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
+    /// One block of the file didn't hash to its published SHA-256, even
+    /// after fetching it again.
+    #[error("sha256 mismatch in block {block}: expected {expected}, got {actual}")]
+    BlockMismatch {
+        block: u64,
+        expected: String,
+        actual: String,
+    },
+
+    /// The body ended after a different number of bytes than its
+    /// `Content-Length` promised.
+    #[error("received {received} of {expected} bytes from {url}")]
+    LengthMismatch {
+        url: Url,
+        expected: u64,
+        received: u64,
+    },
+
+    /// The response's `Content-Type` isn't one the caller accepts, e.g. an
+    /// HTML login page where an archive was expected.
+    #[error(
+        "unexpected content type {} from {url}",
+        content_type.as_deref().unwrap_or("(none)")
+    )]
+    UnexpectedType {
+        url: Url,
+        content_type: Option<String>,
+    },
+
+    /// The file is already there and the conflict policy says not to touch it.
+    #[error("{} already exists", path.display())]
+    Exists { path: PathBuf },
+
+    /// The server's certificate isn't one of the pinned ones, or the URL
+    /// isn't HTTPS. Nothing was sent.
+    #[error("certificate pin check failed for {url}: {reason}")]
+    PinMismatch { url: Url, reason: String },
+
+    /// Another URL of the batch maps to the same file, and numbering is off.
+    #[error("{} is also where another download of this batch goes", path.display())]
+    NameTaken { path: PathBuf },
+
+    /// The server answered `304 Not Modified` to the cached copy's
+    /// validators; the copy is current.
+    #[error("not modified since the cached copy")]
+    NotModified,
+
+    /// The directory to save into doesn't exist, and creating directories
+    /// is turned off.
+    #[error("directory {} doesn't exist", path.display())]
+    MissingDir { path: PathBuf },
+
+    /// The file was saved but couldn't be unpacked, e.g. a corrupt archive
+    /// or one with entries that would land outside its directory.
+    #[error("extract {}: {reason}", path.display())]
+    Extract { path: PathBuf, reason: String },
+
+    /// The file is bigger than the configured maximum size.
+    #[error("larger than the {limit} byte size limit")]
+    TooLarge { limit: u64 },
+
+    /// The server said up front the file has `size` bytes, under or `over`
+    /// a size range the batch was limited to; it's left out, not failed.
+    #[error(
+        "{size} bytes, {} the {limit} byte {}",
+        if *over { "over" } else { "under" },
+        if *over { "maximum" } else { "minimum" }
+    )]
+    OutsideSizeRange { size: u64, limit: u64, over: bool },
+
+    /// The run has received all the bytes it was allowed to in total: a
+    /// queued download never starts, a running one stops if asked to.
+    #[error("the {limit} byte limit on the run's total download size was reached")]
+    OverBudget { limit: u64 },
+
+    /// Not enough free space for the bytes still to come, counting what other
+    /// downloads in flight (`others` of `needed`) have yet to write.
+    #[error(
+        "insufficient disk space in {}: need {needed} bytes{}, {available} available",
+        dir.display(),
+        if *others > 0 { format!(" ({others} for other downloads in progress)") } else { String::new() }
+    )]
+    InsufficientSpace {
+        dir: PathBuf,
+        needed: u64,
+        others: u64,
+        available: u64,
+    },
+
+    /// The server's answer to a resume or range request didn't add up.
+    #[error("{0}")]
+    Protocol(String),
+
+    /// The URL can't be fetched as written, e.g. a malformed `data:` URL.
+    #[error("invalid URL {url}: {reason}")]
+    InvalidUrl { url: Url, reason: String },
+
+    /// A server advertised ranges but answered one with a full body. Segmented
+    /// downloads fall back to a single stream on this, so callers never see it.
+    #[error("server ignored range request")]
+    RangeIgnored,
+
+    /// The batch was cancelled while this download was in flight.
+    #[error("interrupted")]
+    Interrupted,
+
+    /// The task downloading the file panicked, which is a bug; it's reported
+    /// as a failure rather than lost.
+    #[error("download task panicked: {0}")]
+    Panicked(String),
+}
+
+impl DownloadError {
+    /// Wrap an I/O error with what was being done at the time.
+    pub(crate) fn io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> Self {
+        let context = context.into();
+        move |source| DownloadError::Io { context, source }
+    }
+
+    /// Wrap a request that failed before a response came back, telling a
+    /// server that failed the certificate pin check apart from the rest.
+    pub(crate) fn network(url: &Url) -> impl FnOnce(reqwest::Error) -> Self + '_ {
+        move |source| match crate::pin::mismatch(&source) {
+            Some(reason) => DownloadError::PinMismatch {
+                url: url.clone(),
+                reason,
+            },
+            None => DownloadError::Network {
+                url: url.clone(),
+                source,
+            },
+        }
+    }
+
+    /// The message followed by every underlying cause it doesn't already
+    /// spell out, e.g. the DNS or TLS failure behind a network error.
+    pub fn chain(&self) -> String {
+        let mut out = self.to_string();
+        let mut cause = std::error::Error::source(self);
+        while let Some(e) = cause {
+            let msg = e.to_string();
+            if !out.contains(&msg) {
+                out.push_str(": ");
+                out.push_str(&msg);
+            }
+            cause = e.source();
+        }
+        out
+    }
+
+    /// Whether the request never reached a server: DNS, TCP or TLS handshake
+    /// failures, as opposed to anything that went wrong with a response.
+    pub fn is_connect(&self) -> bool {
+        matches!(self, DownloadError::Network { source, .. } if source.is_connect())
+    }
+
+    /// Whether another attempt could plausibly succeed.
+    ///
+    /// Client errors (4xx) are permanent except 408 Request Timeout and 429 Too
+    /// Many Requests; 5xx, network failures, connections lost mid-body,
+    /// timeouts, stalled transfers, truncated bodies and checksum mismatches
+    /// are all worth retrying.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::HttpStatus(e) => {
+                e.status.is_server_error()
+                    || e.status == StatusCode::REQUEST_TIMEOUT
+                    || e.status == StatusCode::TOO_MANY_REQUESTS
+            }
+            // A redirect loop or exceeded limit comes out the same every time
+            DownloadError::Network { source, .. } => !source.is_redirect(),
+            DownloadError::StreamBroken { .. }
+            | DownloadError::Timeout { .. }
+            | DownloadError::TooSlow { .. }
+            | DownloadError::Io { .. }
+            | DownloadError::ChecksumMismatch { .. }
+            | DownloadError::BlockMismatch { .. }
+            | DownloadError::LengthMismatch { .. }
+            | DownloadError::Protocol(_) => true,
+            // Asking again won't change the answer; for RangeIgnored the
+            // caller falls back instead
+            DownloadError::TooLarge { .. }
+            | DownloadError::OutsideSizeRange { .. }
+            | DownloadError::UnexpectedType { .. }
+            | DownloadError::Exists { .. }
+            | DownloadError::NameTaken { .. }
+            | DownloadError::NotModified
+            | DownloadError::MissingDir { .. }
+            | DownloadError::PinMismatch { .. }
+            | DownloadError::OverBudget { .. }
+            | DownloadError::Extract { .. }
+            | DownloadError::InsufficientSpace { .. }
+            | DownloadError::InvalidUrl { .. }
+            | DownloadError::RangeIgnored
+            | DownloadError::Panicked(_)
+            | DownloadError::Interrupted => false,
+        }
+    }
+}
+
+impl From<StatusError> for DownloadError {
+    fn from(e: StatusError) -> Self {
+        DownloadError::HttpStatus(Box::new(e))
+    }
+}
+
+/// A response that came back with a non-2xx status.
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: StatusCode,
+    pub url: Url,
+    /// Server-requested wait from `Retry-After`, honored on 429 and 503.
+    pub retry_after: Option<Duration>,
+    /// Target of a redirect that wasn't followed.
+    pub location: Option<String>,
+}
+
+impl StatusError {
+    pub(crate) fn from_response(resp: &reqwest::Response) -> Self {
+        let status = resp.status();
+        let retry_after = match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => resp
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after),
+            _ => None,
+        };
+        let location = status
+            .is_redirection()
+            .then(|| resp.headers().get(LOCATION)?.to_str().ok())
+            .flatten()
+            // Relative targets are reported as the absolute URL they point at
+            .map(|loc| match resp.url().join(loc) {
+                Ok(abs) => abs.to_string(),
+                Err(_) => loc.to_string(),
+            });
+        StatusError {
+            status,
+            url: resp.url().clone(),
+            retry_after,
+            location,
+        }
+    }
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(to) => write!(f, "{} redirect from {} to {to}", self.status, self.url),
+            None => write!(f, "non-success status {} for {}", self.status, self.url),
+        }
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// `Retry-After` is either delta-seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let when = httpdate::parse_http_date(value).ok()?;
+    Some(
+        when.duration_since(std::time::SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
@@ -0,0 +1,111 @@
+//! `--progress-json`: newline-delimited JSON events for programs wrapping
+//! the downloader.
+
+use mt_downloader::{Monitor, Transfer, TransferState};
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// How often state changes are looked for.
+const POLL: Duration = Duration::from_millis(100);
+
+/// At most one `progress` event per transfer this often.
+const PROGRESS_EVERY: Duration = Duration::from_millis(500);
+
+/// What was last reported for one transfer.
+#[derive(Default, Clone)]
+struct Seen {
+    state: Option<TransferState>,
+    retries: u32,
+    progress_at: Option<Instant>,
+}
+
+/// Write events about `monitor`'s transfers to `out` until `stop` is
+/// cancelled, then once more for whatever finished in the meantime.
+pub async fn run(
+    monitor: Arc<Monitor>,
+    mut out: Box<dyn Write + Send>,
+    stop: CancellationToken,
+) -> io::Result<()> {
+    let mut seen: Vec<Seen> = Vec::new();
+    loop {
+        let stopping = stop.is_cancelled();
+        let transfers = monitor.transfers();
+        seen.resize(transfers.len(), Seen::default());
+        for (t, seen) in transfers.iter().zip(&mut seen) {
+            for event in events(t, seen) {
+                writeln!(out, "{event}")?;
+            }
+        }
+        out.flush()?;
+        if stopping {
+            return Ok(());
+        }
+        tokio::select! {
+            _ = stop.cancelled() => {}
+            _ = tokio::time::sleep(POLL) => {}
+        }
+    }
+}
+
+/// Events for `t` since it looked like `seen`, which is brought up to date.
+fn events(t: &Transfer, seen: &mut Seen) -> Vec<Value> {
+    let mut events = Vec::new();
+    let url = t.url.as_str();
+    let changed = seen.state != Some(t.state);
+    // A transfer can get through several states between two polls
+    let was_queued = seen.state.is_none_or(|s| s == TransferState::Queued);
+    if changed && was_queued && !matches!(t.state, TransferState::Queued | TransferState::Cancelled)
+    {
+        events.push(json!({
+            "event": "started",
+            "url": url,
+            "path": t.path.display().to_string(),
+        }));
+    }
+    if t.retries > seen.retries {
+        events.push(json!({
+            "event": "retry",
+            "url": url,
+            "attempt": t.retries,
+            "error": t.error,
+        }));
+    }
+    let now = Instant::now();
+    let due = seen.progress_at.is_none_or(|at| now - at >= PROGRESS_EVERY);
+    if t.state == TransferState::Active && due {
+        events.push(json!({
+            "event": "progress",
+            "url": url,
+            "bytes": t.received,
+            "total": t.total,
+            "speed": t.bytes_per_sec as u64,
+        }));
+        seen.progress_at = Some(now);
+    }
+    if changed {
+        match t.state {
+            TransferState::Done => events.push(json!({
+                "event": "completed",
+                "url": url,
+                "path": t.path.display().to_string(),
+                "bytes": t.received,
+            })),
+            TransferState::Failed => events.push(json!({
+                "event": "failed",
+                "url": url,
+                "error": t.error,
+            })),
+            TransferState::Cancelled => events.push(json!({
+                "event": "cancelled",
+                "url": url,
+            })),
+            TransferState::Queued | TransferState::Active => {}
+        }
+    }
+    seen.state = Some(t.state);
+    seen.retries = t.retries;
+    events
+}
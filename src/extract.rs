@@ -0,0 +1,133 @@
+//! Unpacking downloaded archives.
+
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{DownloadError, Result};
+
+/// Archive formats we know how to unpack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Archive {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// Name endings for each format, matched ignoring case.
+const EXTENSIONS: [(&str, Archive); 4] = [
+    (".tar.gz", Archive::TarGz),
+    (".tgz", Archive::TarGz),
+    (".tar", Archive::Tar),
+    (".zip", Archive::Zip),
+];
+
+impl Archive {
+    /// What the file at `path` is, going by its name and else by the
+    /// `Content-Type` it was served with.
+    pub(crate) fn detect(path: &Path, content_type: Option<&str>) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if let Some((_, kind)) = EXTENSIONS.iter().find(|(ext, _)| name.ends_with(ext)) {
+            return Some(*kind);
+        }
+        let mime = content_type?.split(';').next()?.trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "application/zip" | "application/x-zip-compressed" => Some(Archive::Zip),
+            "application/x-tar" => Some(Archive::Tar),
+            "application/x-gtar" | "application/x-tgz" => Some(Archive::TarGz),
+            _ => None,
+        }
+    }
+}
+
+/// Directory to unpack the archive at `path` into: next to it, named after
+/// it minus the archive extension, or with `.d` added when it has none.
+pub(crate) fn target_dir(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let lower = name.to_ascii_lowercase();
+    let stem = EXTENSIONS
+        .iter()
+        .find(|(ext, _)| lower.ends_with(ext) && lower.len() > ext.len())
+        .map(|(ext, _)| name[..name.len() - ext.len()].to_string())
+        .unwrap_or_else(|| format!("{name}.d"));
+    path.with_file_name(stem)
+}
+
+/// Unpack the `kind` archive at `path` into `dir`, returning how many files
+/// it held. Blocking; run it off the async workers.
+///
+/// An entry whose path is absolute or climbs out with `..` fails the whole
+/// extraction rather than being written anywhere.
+pub(crate) fn extract(path: &Path, kind: Archive, dir: &Path) -> Result<u64> {
+    let fail = |reason: String| DownloadError::Extract {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let file = File::open(path).map_err(|e| fail(e.to_string()))?;
+    std::fs::create_dir_all(dir).map_err(|e| fail(format!("create {}: {e}", dir.display())))?;
+    match kind {
+        Archive::Zip => extract_zip(file, dir).map_err(fail),
+        Archive::Tar => extract_tar(file, dir).map_err(fail),
+        Archive::TarGz => extract_tar(GzDecoder::new(file), dir).map_err(fail),
+    }
+}
+
+fn extract_zip(file: File, dir: &Path) -> Result<u64, String> {
+    let mut zip = zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+    let mut files = 0;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(|e| e.to_string())?;
+        let Some(rel) = entry.enclosed_name() else {
+            let name = entry.name().map(|n| n.into_owned()).unwrap_or_default();
+            return Err(format!("entry {name} escapes the target directory"));
+        };
+        let out = dir.join(rel);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&out).map_err(|e| format!("create {}: {e}", out.display()))?;
+            continue;
+        }
+        if let Some(parent) = out.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("create {}: {e}", parent.display()))?;
+        }
+        // Symlinks come out as small files holding their target, which can't
+        // point anywhere
+        let mut dest = File::create(&out).map_err(|e| format!("create {}: {e}", out.display()))?;
+        io::copy(&mut entry, &mut dest).map_err(|e| format!("write {}: {e}", out.display()))?;
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            let perms = std::fs::Permissions::from_mode(mode & 0o755);
+            let _ = std::fs::set_permissions(&out, perms);
+        }
+        files += 1;
+    }
+    Ok(files)
+}
+
+fn extract_tar(reader: impl Read, dir: &Path) -> Result<u64, String> {
+    let mut tar = tar::Archive::new(reader);
+    let mut files = 0;
+    for entry in tar.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let rel = entry.path().map_err(|e| e.to_string())?.into_owned();
+        let inside = rel
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        // unpack_in also refuses to follow a symlink out of `dir`
+        if !inside || !entry.unpack_in(dir).map_err(|e| e.to_string())? {
+            return Err(format!(
+                "entry {} escapes the target directory",
+                rel.display()
+            ));
+        }
+        if entry.header().entry_type().is_file() {
+            files += 1;
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,119 @@
+//! curl-style URL globbing: `[001-250]`, `[a-z]` and `{one,two}`.
+
+use anyhow::{anyhow, bail, Result};
+
+/// More URLs than this from one pattern is almost certainly a typo.
+const MAX_EXPANSION: usize = 100_000;
+
+/// One piece of a pattern: fixed text or a set of alternatives.
+enum Part {
+    Literal(String),
+    Choice(Vec<String>),
+}
+
+/// Expand every `[start-end]`, `[start-end:step]` and `{a,b,c}` in `pattern`
+/// into the full list of URLs, in order.
+///
+/// Numeric ranges keep the width of a zero-padded start (`[08-10]` gives
+/// `08`, `09`, `10`); letter ranges run within one case. A backslash makes
+/// the next character literal, so `\[` survives untouched.
+pub fn expand_url_pattern(pattern: &str) -> Result<Vec<String>> {
+    let mut urls = vec![String::new()];
+    for part in parse(pattern)? {
+        urls = match part {
+            Part::Literal(text) => urls.into_iter().map(|u| u + &text).collect(),
+            Part::Choice(options) => {
+                if urls.len().saturating_mul(options.len()) > MAX_EXPANSION {
+                    bail!("'{pattern}' expands to more than {MAX_EXPANSION} URLs");
+                }
+                urls.iter()
+                    .flat_map(|u| options.iter().map(move |o| format!("{u}{o}")))
+                    .collect()
+            }
+        };
+    }
+    Ok(urls)
+}
+
+fn parse(pattern: &str) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let close = match c {
+            '\\' => {
+                literal.extend(chars.next());
+                continue;
+            }
+            '[' => ']',
+            '{' => '}',
+            _ => {
+                literal.push(c);
+                continue;
+            }
+        };
+        let mut body = String::new();
+        loop {
+            match chars.next() {
+                Some(d) if d == close => break,
+                Some(d) => body.push(d),
+                None => bail!("unmatched '{c}' in '{pattern}'"),
+            }
+        }
+        parts.push(Part::Literal(std::mem::take(&mut literal)));
+        parts.push(Part::Choice(if c == '[' {
+            range(&body).map_err(|e| anyhow!("bad range '[{body}]' in '{pattern}': {e}"))?
+        } else {
+            body.split(',').map(str::to_string).collect()
+        }));
+    }
+    parts.push(Part::Literal(literal));
+    Ok(parts)
+}
+
+/// The values of `start-end` or `start-end:step`.
+fn range(body: &str) -> Result<Vec<String>> {
+    let (span, step) = match body.split_once(':') {
+        Some((span, step)) => (span, step.parse::<usize>()?),
+        None => (body, 1),
+    };
+    if step == 0 {
+        bail!("step must be at least 1");
+    }
+    let (start, end) = span
+        .split_once('-')
+        .ok_or_else(|| anyhow!("expected start-end"))?;
+
+    if let (Ok(a), Ok(b)) = (start.parse::<u64>(), end.parse::<u64>()) {
+        if a > b {
+            bail!("start is after end");
+        }
+        if (b - a) / step as u64 >= MAX_EXPANSION as u64 {
+            bail!("more than {MAX_EXPANSION} values");
+        }
+        let width = if start.len() > 1 && start.starts_with('0') {
+            start.len()
+        } else {
+            0
+        };
+        return Ok((a..=b)
+            .step_by(step)
+            .map(|n| format!("{n:0width$}"))
+            .collect());
+    }
+
+    let (mut a, mut b) = (start.chars(), end.chars());
+    match (a.next(), a.next(), b.next(), b.next()) {
+        (Some(a), None, Some(b), None)
+            if a.is_ascii_alphabetic()
+                && b.is_ascii_alphabetic()
+                && a.is_ascii_lowercase() == b.is_ascii_lowercase() =>
+        {
+            if a > b {
+                bail!("start is after end");
+            }
+            Ok((a..=b).step_by(step).map(String::from).collect())
+        }
+        _ => bail!("expected numbers or single letters of the same case"),
+    }
+}
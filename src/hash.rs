@@ -0,0 +1,122 @@
+//! Digests recorded in a checksum manifest as files are saved.
+
+use anyhow::{bail, Result};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::download::to_hex;
+use crate::error::DownloadError;
+
+/// Algorithm for the digests written by
+/// [`write_checksums`](crate::DownloaderBuilder::write_checksums).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Lowercase name, as accepted by `from_str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "sha256" => HashAlgorithm::Sha256,
+            "sha1" => HashAlgorithm::Sha1,
+            "md5" => HashAlgorithm::Md5,
+            "blake3" => HashAlgorithm::Blake3,
+            _ => bail!("unknown hash '{s}', expected sha256, sha1, md5 or blake3"),
+        })
+    }
+}
+
+/// An in-progress digest of whichever [`HashAlgorithm`].
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// The digest as lowercase hex.
+    pub(crate) fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Sha256(h) => to_hex(&h.finalize()),
+            Hasher::Sha1(h) => to_hex(&h.finalize()),
+            Hasher::Md5(h) => to_hex(&h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// A `sha256sum`-style manifest that gets a line per file as downloads finish.
+#[derive(Debug)]
+pub(crate) struct Manifest {
+    path: PathBuf,
+    /// Concurrent downloads append one at a time so lines never interleave.
+    lock: Mutex<()>,
+}
+
+impl Manifest {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Manifest {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Append `<digest>  <name>`, creating the manifest if needed.
+    pub(crate) fn append(&self, digest: &str, name: &Path) -> Result<(), DownloadError> {
+        let _guard = self.lock.lock().unwrap();
+        let line = format!("{digest}  {}\n", name.display());
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(line.as_bytes()))
+            .map_err(DownloadError::io(format!(
+                "write checksums to {}",
+                self.path.display()
+            )))
+    }
+}
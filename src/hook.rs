@@ -0,0 +1,57 @@
+//! User commands run on each file once its download has settled.
+
+use std::io::Write;
+use std::path::Path;
+use tokio::process::Command;
+use tracing::info;
+
+use crate::Shared;
+
+/// Run `command` through the shell for the file at `path`, with `{}` standing
+/// for the path as in `find -exec` (or the path added at the end when there's
+/// no `{}`). The command's output is passed on once it exits; an error says
+/// why it failed.
+pub(crate) async fn run(shared: &Shared, command: &str, path: &Path) -> Result<(), String> {
+    info!(command, path = %path.display(), "running hook");
+    let output = shell(command, path)
+        .output()
+        .await
+        .map_err(|e| format!("couldn't run `{command}`: {e}"))?;
+    if !output.stdout.is_empty() || !output.stderr.is_empty() {
+        shared.multi.suspend(|| {
+            let _ = std::io::stdout().write_all(&output.stdout);
+            let _ = std::io::stderr().write_all(&output.stderr);
+        });
+    }
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("`{command}` {}", output.status))
+    }
+}
+
+/// The path goes in as `$1`, so it's never parsed as shell syntax.
+#[cfg(not(windows))]
+fn shell(command: &str, path: &Path) -> Command {
+    let script = if command.contains("{}") {
+        command.replace("{}", "\"$1\"")
+    } else {
+        format!("{command} \"$1\"")
+    };
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(script).arg("sh").arg(path);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str, path: &Path) -> Command {
+    let quoted = format!("\"{}\"", path.display());
+    let line = if command.contains("{}") {
+        command.replace("{}", &quoted)
+    } else {
+        format!("{command} {quoted}")
+    };
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(line);
+    cmd
+}
@@ -0,0 +1,1828 @@
+//! Async multi-file downloader.
+//!
+//! [`Downloader`] fetches a batch of URLs with bounded concurrency, retries
+//! transient failures with exponential backoff, resumes interrupted transfers
+//! from `.part` files and can verify SHA-256 checksums as bytes arrive.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use mt_downloader::Downloader;
+//!
+//! let dl = Downloader::builder().out_dir("downloads").concurrency(8).build()?;
+//! let urls = vec!["https://example.com/file.zip".parse()?];
+//! for res in dl.download(&urls).await {
+//!     println!("{} -> {} ({} bytes)", res.url, res.path.display(), res.bytes);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod accept;
+mod autotune;
+mod blocks;
+mod cache;
+mod dedup;
+mod download;
+mod error;
+mod extract;
+mod glob;
+mod hash;
+mod hook;
+mod local;
+mod monitor;
+mod naming;
+mod netrc;
+mod pin;
+mod ratelimit;
+#[cfg(feature = "s3")]
+mod s3;
+mod segmented;
+mod session;
+mod space;
+mod spec;
+mod template;
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use indicatif::{HumanBytes, MultiProgress, ProgressDrawTarget};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::Method;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{AcquireError, Semaphore};
+use tokio::task::JoinError;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+use url::Url;
+
+use crate::accept::TypeFilter;
+use crate::cache::Validators;
+use crate::dedup::{Dedup, Link};
+use crate::download::{
+    download_with_retries, fetch_bytes, is_complete, is_up_to_date, probe, save_headers,
+    with_retries, BatchProgress, Probed, Saved,
+};
+use crate::error::Result;
+use crate::extract::Archive;
+use crate::hash::Manifest;
+use crate::monitor::Tracker;
+use crate::naming::{
+    reserve_output_path, reserve_unnumbered, sanitize_file_name, saved_name_from_url, url_dirs,
+};
+
+pub use crate::blocks::BlockHashes;
+pub use crate::cache::HttpCache;
+pub use crate::error::{DownloadError, StatusError};
+pub use crate::glob::expand_url_pattern;
+pub use crate::hash::HashAlgorithm;
+pub use crate::monitor::{BatchStats, Monitor, Transfer, TransferState};
+pub use crate::naming::{file_name_from_url, NumberBy, OnConflict};
+pub use crate::netrc::Netrc;
+pub use crate::pin::pinned_tls_config;
+pub use crate::ratelimit::RateLimiter;
+#[cfg(feature = "s3")]
+pub use crate::s3::AwsCredentials;
+pub use crate::segmented::SegmentTiming;
+pub use crate::session::Session;
+pub use crate::spec::DownloadSpec;
+pub use crate::template::OutputTemplate;
+
+/// Per-run settings shared by every download task.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Options {
+    pub(crate) retries: u32,
+    pub(crate) backoff_ms: u64,
+    /// Attempts and base backoff for failures to connect at all, counted
+    /// apart from `retries`; `None` to count them in with the rest.
+    pub(crate) connect_retries: Option<u32>,
+    pub(crate) connect_backoff_ms: Option<u64>,
+    pub(crate) jitter: bool,
+    pub(crate) max_backoff_ms: Option<u64>,
+    pub(crate) max_retry_time: Option<Duration>,
+    /// How long a whole batch may run before it winds down as if cancelled.
+    pub(crate) deadline: Option<Duration>,
+    pub(crate) on_conflict: OnConflict,
+    pub(crate) number_by: NumberBy,
+    pub(crate) keep_partial: bool,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) clear_finished: bool,
+    pub(crate) refresh: Duration,
+    pub(crate) quiet: bool,
+    pub(crate) timestamping: bool,
+    pub(crate) segments: usize,
+    pub(crate) adjust_extension: bool,
+    /// Unpack archives once saved, and maybe delete them afterwards.
+    pub(crate) extract: bool,
+    pub(crate) remove_archive: bool,
+    /// The caller named the output file; keep that name exactly.
+    pub(crate) fixed_name: bool,
+    pub(crate) preserve_path: bool,
+    pub(crate) cut_dirs: usize,
+    /// Create missing directories to save into, rather than fail.
+    pub(crate) create_dirs: bool,
+    /// Write each response's status line and headers next to its file.
+    pub(crate) save_headers: bool,
+    pub(crate) max_size: Option<u64>,
+    /// Sizes the server may announce for a file to be downloaded at all.
+    pub(crate) min_remote_size: Option<u64>,
+    pub(crate) max_remote_size: Option<u64>,
+    /// Bytes the whole run may download; queued files past it are skipped,
+    /// and with `abort_over_total` running ones stop too.
+    pub(crate) max_total_size: Option<u64>,
+    pub(crate) abort_over_total: bool,
+    pub(crate) max_rate_per_file: Option<u64>,
+    /// Bytes gathered in memory before each write to disk; 0 writes every
+    /// chunk as it arrives.
+    pub(crate) buffer_size: usize,
+    /// Slowest average speed tolerated over a whole `min_rate_time`.
+    pub(crate) min_rate: Option<u64>,
+    pub(crate) min_rate_time: Duration,
+    pub(crate) compressed: bool,
+    pub(crate) fail_fast: bool,
+    pub(crate) dry_run: bool,
+    /// Digest to compute for every saved file, for the checksum manifest.
+    pub(crate) digest: Option<HashAlgorithm>,
+}
+
+/// Handles shared by every task in a batch.
+#[derive(Debug, Clone)]
+pub(crate) struct Shared {
+    pub(crate) client: reqwest::Client,
+    pub(crate) multi: MultiProgress,
+    /// Global bandwidth cap, if any.
+    pub(crate) limiter: Option<Arc<RateLimiter>>,
+    /// Cap for the one file a task is fetching, segments included.
+    pub(crate) file_limiter: Option<Arc<RateLimiter>>,
+    pub(crate) auth: Option<Auth>,
+    /// Per-host Basic credentials for requests without `auth`.
+    pub(crate) netrc: Option<Arc<Netrc>>,
+    /// User-Agents to pick one of at random for each request; empty to
+    /// leave the client's own.
+    pub(crate) user_agents: Arc<Vec<HeaderValue>>,
+    /// Signs requests to S3 hosts, when there are no other credentials.
+    #[cfg(feature = "s3")]
+    pub(crate) aws: Option<Arc<AwsCredentials>>,
+    /// Stops this task; a child of `batch_cancel` when a monitor can cancel
+    /// tasks one at a time, else the same token.
+    pub(crate) cancel: CancellationToken,
+    pub(crate) batch_cancel: CancellationToken,
+    /// Output paths claimed by this batch, whether or not they exist yet.
+    pub(crate) reserved: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Method for the request that fetches each file.
+    pub(crate) method: Method,
+    /// Body sent with it, if any.
+    pub(crate) body: Option<Bytes>,
+    /// Content types a response must have to be saved.
+    pub(crate) accept: Arc<TypeFilter>,
+    /// Bytes that downloads in flight expect to write but haven't yet.
+    pub(crate) pending_bytes: Arc<AtomicU64>,
+    /// Failed attempts so far, which auto-concurrency backs off on.
+    pub(crate) failures: Arc<AtomicU64>,
+    /// Draw progress bars in color.
+    pub(crate) color: bool,
+    /// Totals bar for the current batch.
+    pub(crate) batch: BatchProgress,
+    /// Headers for this task's file alone, on top of the client's.
+    pub(crate) headers: Option<Arc<HeaderMap>>,
+    /// Whole-request timeout for this task's file, in place of the client's.
+    pub(crate) timeout: Option<Duration>,
+    /// This task's entry in the batch's monitor, if one is watching.
+    pub(crate) tracker: Option<Tracker>,
+    /// Validators of the cached copy of this task's file, to make its
+    /// request conditional.
+    pub(crate) validators: Option<Arc<Validators>>,
+}
+
+/// Credentials sent with every request.
+#[derive(Debug, Clone)]
+pub enum Auth {
+    /// HTTP Basic; the password may be omitted.
+    Basic {
+        user: String,
+        password: Option<String>,
+    },
+    /// `Authorization: Bearer <token>`.
+    Bearer(String),
+}
+
+/// What happened to one URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The file was downloaded and saved.
+    Saved,
+    /// Timestamping found the local copy already current, or the conflict
+    /// policy left an existing file alone; nothing was fetched.
+    UpToDate,
+    /// The server answered `304 Not Modified` to the validators of the copy
+    /// in the [`http_cache`](DownloaderBuilder::http_cache), which is at
+    /// [`path`](DownloadResult::path); nothing was fetched.
+    NotModified,
+    /// Every attempt failed; see [`DownloadResult::error`].
+    Failed,
+    /// Cancelled mid-transfer; the `.part` file is kept for a later resume.
+    Interrupted,
+    /// Cancelled before it started.
+    Skipped,
+    /// Left out, its announced size being outside the range asked for; see
+    /// [`DownloadResult::error`].
+    Filtered,
+    /// Dry run: the URL answered and would be saved to
+    /// [`path`](DownloadResult::path).
+    Planned,
+}
+
+impl Status {
+    /// Lowercase name used in machine-readable output.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Saved => "ok",
+            Status::UpToDate => "up-to-date",
+            Status::NotModified => "not-modified",
+            Status::Failed => "failed",
+            Status::Interrupted => "interrupted",
+            Status::Skipped => "skipped",
+            Status::Filtered => "filtered",
+            Status::Planned => "planned",
+        }
+    }
+}
+
+/// Outcome of one URL in a batch.
+#[derive(Debug)]
+pub struct DownloadResult {
+    pub url: Url,
+    pub status: Status,
+    /// Where the file was saved, or where it would have gone on failure.
+    pub path: PathBuf,
+    /// Bytes received by the attempt that completed the file.
+    pub bytes: u64,
+    /// Size the server reported for the file in a dry run, if it said.
+    pub size: Option<u64>,
+    /// `Content-Type` the server reported in a dry run.
+    pub content_type: Option<String>,
+    /// `Last-Modified` the server reported in a dry run.
+    pub last_modified: Option<SystemTime>,
+    /// Whether the server offered byte ranges (so resume and segments work),
+    /// checked in a dry run.
+    pub accept_ranges: Option<bool>,
+    /// The URL the file was finally fetched from, when a mirror or redirects
+    /// led elsewhere.
+    pub final_url: Option<Url>,
+    /// The mirror that served the file, when it wasn't `url` itself.
+    pub mirror: Option<Url>,
+    /// Requests made, including the final one.
+    pub attempts: u32,
+    /// Hex digest of the saved file, when writing a checksum manifest or
+    /// deduplicating.
+    pub digest: Option<String>,
+    /// An earlier file of the batch with the same content, which this one
+    /// was turned into a link to.
+    pub same_as: Option<PathBuf>,
+    /// Directory the file was unpacked into, when it was an archive and
+    /// extraction was asked for.
+    pub extracted: Option<PathBuf>,
+    /// Time from sending the request that saved the file to its response
+    /// headers. Not set for local files.
+    pub ttfb: Option<Duration>,
+    /// How long the attempt that saved the file took, from request to the
+    /// file in place; earlier failed attempts aren't included.
+    pub elapsed: Option<Duration>,
+    /// How each byte range of a segmented download went; empty for any
+    /// other kind.
+    pub segments: Vec<SegmentTiming>,
+    pub error: Option<DownloadError>,
+    /// Why the [`exec`](DownloaderBuilder::exec) or
+    /// [`exec_on_failure`](DownloaderBuilder::exec_on_failure) command run
+    /// for this file failed, if it did.
+    pub exec_error: Option<String>,
+}
+
+impl DownloadResult {
+    fn new(url: Url, status: Status, path: PathBuf) -> Self {
+        DownloadResult {
+            url,
+            status,
+            path,
+            bytes: 0,
+            size: None,
+            content_type: None,
+            last_modified: None,
+            accept_ranges: None,
+            final_url: None,
+            mirror: None,
+            attempts: 0,
+            digest: None,
+            same_as: None,
+            extracted: None,
+            ttfb: None,
+            elapsed: None,
+            segments: Vec::new(),
+            error: None,
+            exec_error: None,
+        }
+    }
+
+    /// Whether the file is now present and complete.
+    pub fn is_ok(&self) -> bool {
+        matches!(
+            self.status,
+            Status::Saved | Status::UpToDate | Status::NotModified | Status::Planned
+        )
+    }
+}
+
+/// Builder for [`Downloader`]; every setting has a sensible default.
+#[derive(Debug, Clone)]
+pub struct DownloaderBuilder {
+    client: Option<reqwest::Client>,
+    out_dir: PathBuf,
+    concurrency: usize,
+    auto_concurrency: Option<usize>,
+    per_host: Option<usize>,
+    template: Option<OutputTemplate>,
+    output_file: Option<PathBuf>,
+    checksums: HashMap<String, String>,
+    block_hashes: HashMap<String, BlockHashes>,
+    mirrors: HashMap<Url, Vec<Url>>,
+    manifest: Option<PathBuf>,
+    session: Option<Arc<Session>>,
+    cache: Option<Arc<HttpCache>>,
+    monitor: Option<Arc<Monitor>>,
+    stats: Arc<BatchStats>,
+    hash: HashAlgorithm,
+    dedup: bool,
+    exec: Option<String>,
+    exec_on_failure: Option<String>,
+    max_rate: Option<u64>,
+    auth: Option<Auth>,
+    netrc: Option<Netrc>,
+    pins: Vec<[u8; 32]>,
+    user_agents: Vec<HeaderValue>,
+    #[cfg(feature = "s3")]
+    aws: Option<AwsCredentials>,
+    method: Method,
+    body: Option<Bytes>,
+    accept_types: Vec<String>,
+    cancel: CancellationToken,
+    multi: Option<MultiProgress>,
+    color: bool,
+    opts: Options,
+}
+
+impl Default for DownloaderBuilder {
+    fn default() -> Self {
+        DownloaderBuilder {
+            client: None,
+            out_dir: PathBuf::from("."),
+            concurrency: 4,
+            auto_concurrency: None,
+            per_host: None,
+            template: None,
+            output_file: None,
+            checksums: HashMap::new(),
+            block_hashes: HashMap::new(),
+            mirrors: HashMap::new(),
+            manifest: None,
+            session: None,
+            cache: None,
+            monitor: None,
+            stats: Arc::default(),
+            hash: HashAlgorithm::Sha256,
+            dedup: false,
+            exec: None,
+            exec_on_failure: None,
+            max_rate: None,
+            auth: None,
+            netrc: None,
+            pins: Vec::new(),
+            user_agents: Vec::new(),
+            #[cfg(feature = "s3")]
+            aws: None,
+            method: Method::GET,
+            body: None,
+            accept_types: Vec::new(),
+            cancel: CancellationToken::new(),
+            multi: None,
+            color: true,
+            opts: Options {
+                retries: 3,
+                backoff_ms: 500,
+                connect_retries: None,
+                connect_backoff_ms: None,
+                jitter: false,
+                max_backoff_ms: None,
+                max_retry_time: None,
+                deadline: None,
+                on_conflict: OnConflict::Rename,
+                number_by: NumberBy::UrlOrder,
+                keep_partial: false,
+                read_timeout: None,
+                clear_finished: false,
+                refresh: Duration::from_millis(100),
+                quiet: false,
+                timestamping: false,
+                segments: 1,
+                adjust_extension: false,
+                extract: false,
+                remove_archive: false,
+                fixed_name: false,
+                preserve_path: false,
+                cut_dirs: 0,
+                create_dirs: true,
+                save_headers: false,
+                max_size: None,
+                min_remote_size: None,
+                max_remote_size: None,
+                max_total_size: None,
+                abort_over_total: false,
+                max_rate_per_file: None,
+                buffer_size: 0,
+                min_rate: None,
+                min_rate_time: Duration::from_secs(30),
+                compressed: false,
+                fail_fast: false,
+                dry_run: false,
+                digest: None,
+            },
+        }
+    }
+}
+
+impl DownloaderBuilder {
+    /// HTTP client to use; timeouts and other connection settings live here.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Directory files are saved into. It must already exist.
+    pub fn out_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.out_dir = dir.into();
+        self
+    }
+
+    /// Max downloads in flight at once. Each holds one open file and one
+    /// connection, or one of each per segment with
+    /// [`segments`](Self::segments), plus up to twice its
+    /// [`buffer_size`](Self::buffer_size) of memory per file handle.
+    pub fn concurrency(mut self, n: usize) -> Self {
+        self.concurrency = n.max(1);
+        self
+    }
+
+    /// Ignore [`concurrency`](Self::concurrency) and size the pool from
+    /// throughput instead: start with two downloads, add one while combined
+    /// throughput keeps rising, halve on failures, never exceed `max`.
+    pub fn auto_concurrency(mut self, max: Option<usize>) -> Self {
+        self.auto_concurrency = max.map(|n| n.max(1));
+        self
+    }
+
+    /// Max downloads in flight against any one host, on top of `concurrency`.
+    pub fn per_host(mut self, n: Option<usize>) -> Self {
+        self.per_host = n.map(|n| n.max(1));
+        self
+    }
+
+    /// Attempts per file before giving up, the first one included; `0` and
+    /// `1` both mean a single attempt with no retries.
+    pub fn retries(mut self, n: u32) -> Self {
+        self.opts.retries = n;
+        self
+    }
+
+    /// Base backoff; the delay doubles after each failed attempt.
+    pub fn backoff_ms(mut self, ms: u64) -> Self {
+        self.opts.backoff_ms = ms;
+        self
+    }
+
+    /// Attempts to connect, the first one included, kept apart from
+    /// [`retries`](Self::retries): DNS, TCP and TLS handshake failures count
+    /// only against these, anything that goes wrong after a response only
+    /// against `retries`. `None` (the default) counts both together.
+    pub fn connect_retries(mut self, n: Option<u32>) -> Self {
+        self.opts.connect_retries = n;
+        self
+    }
+
+    /// Base backoff after a failure to connect, when
+    /// [`connect_retries`](Self::connect_retries) is set; defaults to
+    /// [`backoff_ms`](Self::backoff_ms). It doubles the same way.
+    pub fn connect_backoff_ms(mut self, ms: Option<u64>) -> Self {
+        self.opts.connect_backoff_ms = ms;
+        self
+    }
+
+    /// Randomize each backoff to somewhere in `[0, delay]`.
+    pub fn jitter(mut self, on: bool) -> Self {
+        self.opts.jitter = on;
+        self
+    }
+
+    /// Cap on a single backoff delay.
+    pub fn max_backoff_ms(mut self, ms: Option<u64>) -> Self {
+        self.opts.max_backoff_ms = ms;
+        self
+    }
+
+    /// Stop retrying a URL once this much time has passed since its first
+    /// attempt, backoffs included, even with attempts left.
+    pub fn max_retry_time(mut self, budget: Option<Duration>) -> Self {
+        self.opts.max_retry_time = budget;
+        self
+    }
+
+    /// Bound each batch to this long from its start. Once it passes, queued
+    /// files are skipped and transfers in flight stop, keeping their `.part`
+    /// files, just as on [`cancel_token`](Self::cancel_token).
+    pub fn deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.opts.deadline = deadline;
+        self
+    }
+
+    /// What to do about files that already exist; by default a download goes
+    /// to `name (N).ext` instead.
+    pub fn on_conflict(mut self, policy: OnConflict) -> Self {
+        self.opts.on_conflict = policy;
+        self
+    }
+
+    /// How URLs of a batch that map to the same file name are told apart;
+    /// by default they're numbered in input order.
+    pub fn number_by(mut self, number_by: NumberBy) -> Self {
+        self.opts.number_by = number_by;
+        self
+    }
+
+    /// Replace existing files instead of saving to `name (N).ext`.
+    #[deprecated(note = "use `on_conflict(OnConflict::Overwrite)`")]
+    pub fn overwrite(mut self, on: bool) -> Self {
+        self.opts.on_conflict = if on {
+            OnConflict::Overwrite
+        } else {
+            OnConflict::Rename
+        };
+        self
+    }
+
+    /// Leave `.part` files of failed downloads in place for a later resume.
+    pub fn keep_partial(mut self, on: bool) -> Self {
+        self.opts.keep_partial = on;
+        self
+    }
+
+    /// Gather up to `bytes` of each download in memory and write them to
+    /// disk together, instead of writing every network chunk as it comes;
+    /// fewer, larger writes, at up to twice `bytes` of memory per open file
+    /// since the file copies each write (up to 2 MiB of it) once more. 0,
+    /// the default, turns it off.
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.opts.buffer_size = bytes;
+        self
+    }
+
+    /// Abort a transfer when no bytes arrive for this long.
+    pub fn read_timeout(mut self, idle: Option<Duration>) -> Self {
+        self.opts.read_timeout = idle;
+        self
+    }
+
+    /// Remove progress bars once their download finishes.
+    pub fn clear_finished(mut self, on: bool) -> Self {
+        self.opts.clear_finished = on;
+        self
+    }
+
+    /// How often progress bars tick on their own (default 100 ms); raise it
+    /// to spend less time redrawing in big batches or on slow terminals.
+    pub fn refresh(mut self, every: Duration) -> Self {
+        self.opts.refresh = every;
+        self
+    }
+
+    /// Give saved files the server's `Last-Modified` time as their mtime, and
+    /// skip files whose local copy is already as new and the same size.
+    /// Existing files are replaced rather than renamed.
+    pub fn timestamping(mut self, on: bool) -> Self {
+        self.opts.timestamping = on;
+        self
+    }
+
+    /// Skip files already present with the size the server reports; a
+    /// shorter one is resumed, a longer one replaced. Makes re-running a batch
+    /// pick up where it left off instead of saving `name (1).ext` copies.
+    /// Same as `on_conflict(OnConflict::Resume)`.
+    pub fn skip_existing(mut self, on: bool) -> Self {
+        if on {
+            self.opts.on_conflict = OnConflict::Resume;
+        } else if self.opts.on_conflict == OnConflict::Resume {
+            self.opts.on_conflict = OnConflict::Rename;
+        }
+        self
+    }
+
+    /// Split each file into this many byte ranges fetched in parallel, when
+    /// the server supports range requests.
+    pub fn segments(mut self, n: usize) -> Self {
+        self.opts.segments = n.max(1);
+        self
+    }
+
+    /// Save `https://host/a/b/file` as `out_dir/a/b/file` rather than
+    /// `out_dir/file`, creating directories as needed.
+    pub fn preserve_path(mut self, on: bool) -> Self {
+        self.opts.preserve_path = on;
+        self
+    }
+
+    /// Create the directories files are saved into when missing (the
+    /// default); off, a file whose directory doesn't exist yet fails instead,
+    /// so a mistyped path can't scatter files into a fresh tree.
+    pub fn create_dirs(mut self, on: bool) -> Self {
+        self.opts.create_dirs = on;
+        self
+    }
+
+    /// Write the status line and headers of the response each file came in
+    /// to `<file>.headers` next to it, after any redirects. Segmented
+    /// downloads record their `HEAD` response.
+    pub fn save_headers(mut self, on: bool) -> Self {
+        self.opts.save_headers = on;
+        self
+    }
+
+    /// With [`preserve_path`](Self::preserve_path), drop this many leading
+    /// directories from each URL path.
+    pub fn cut_dirs(mut self, n: usize) -> Self {
+        self.opts.cut_dirs = n;
+        self
+    }
+
+    /// Name files from `template` instead of the URL's last segment; this
+    /// takes over from [`preserve_path`](Self::preserve_path).
+    pub fn output_template(mut self, template: Option<OutputTemplate>) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Save to exactly `name` under the output directory, ignoring the URL,
+    /// the server's suggested name, `(N)` suffixes and added extensions. Meant
+    /// for single-URL batches: every URL would write the same file.
+    pub fn output_file(mut self, name: Option<PathBuf>) -> Self {
+        self.opts.fixed_name = name.is_some();
+        self.output_file = name;
+        self
+    }
+
+    /// Append an extension matching the response's `Content-Type` to file
+    /// names that have none.
+    pub fn adjust_extension(mut self, on: bool) -> Self {
+        self.opts.adjust_extension = on;
+        self
+    }
+
+    /// Unpack each saved `.zip`, `.tar`, `.tar.gz` or `.tgz` file (or one
+    /// served as such) into a directory next to it, named after it minus the
+    /// extension. Entries that would land outside that directory fail the
+    /// download.
+    pub fn extract(mut self, on: bool) -> Self {
+        self.opts.extract = on;
+        self
+    }
+
+    /// With [`extract`](Self::extract), delete each archive once it's
+    /// unpacked.
+    pub fn remove_archive(mut self, on: bool) -> Self {
+        self.opts.remove_archive = on;
+        self
+    }
+
+    /// Tell the downloader that `client` decodes compressed responses
+    /// (reqwest's `gzip`/`brotli`/`deflate`). Resuming and segmented
+    /// downloads are skipped, as byte ranges would refer to the encoded body.
+    ///
+    /// Checksums, expected or written, are always of the bytes saved: the
+    /// decoded body when this is on, exactly what the server sent when off.
+    pub fn compressed(mut self, on: bool) -> Self {
+        self.opts.compressed = on;
+        self
+    }
+
+    /// Refuse files larger than this many bytes, stopping mid-transfer when
+    /// the server didn't say up front. The partial file is always removed.
+    pub fn max_size(mut self, bytes: Option<u64>) -> Self {
+        self.opts.max_size = bytes;
+        self
+    }
+
+    /// Leave out files the server says are smaller than this many bytes,
+    /// such as error pages served with a `200`, as [`Status::Filtered`].
+    /// Files of unknown size are downloaded.
+    pub fn min_remote_size(mut self, bytes: Option<u64>) -> Self {
+        self.opts.min_remote_size = bytes;
+        self
+    }
+
+    /// Leave out files the server says are larger than this many bytes, as
+    /// [`Status::Filtered`]. Unlike [`max_size`](Self::max_size) that isn't
+    /// a failure, and files of unknown size are downloaded whole.
+    pub fn max_remote_size(mut self, bytes: Option<u64>) -> Self {
+        self.opts.max_remote_size = bytes;
+        self
+    }
+
+    /// Stop starting downloads once this many bytes have been received in
+    /// total, counted across every batch sharing the [`stats`](Self::stats).
+    /// Files still queued then are skipped with [`DownloadError::OverBudget`].
+    pub fn max_total_size(mut self, bytes: Option<u64>) -> Self {
+        self.opts.max_total_size = bytes;
+        self
+    }
+
+    /// Also stop downloads in flight once they take the total past
+    /// [`max_total_size`](Self::max_total_size). They end up interrupted,
+    /// keeping their `.part` file unless segmented.
+    pub fn abort_over_total(mut self, on: bool) -> Self {
+        self.opts.abort_over_total = on;
+        self
+    }
+
+    /// Cap on combined throughput of all downloads, in bytes per second.
+    pub fn max_rate(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.max_rate = bytes_per_sec;
+        self
+    }
+
+    /// Cap on each download's own throughput, in bytes per second, on top of
+    /// [`max_rate`](Self::max_rate).
+    pub fn max_rate_per_file(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.opts.max_rate_per_file = bytes_per_sec;
+        self
+    }
+
+    /// Fail an attempt whose average speed over a whole
+    /// [`min_rate_time`](Self::min_rate_time) falls below this many bytes
+    /// per second, so the retry can find a better connection. Unlike
+    /// [`read_timeout`](Self::read_timeout) this catches transfers that
+    /// trickle rather than stop. Keep it under any rate caps.
+    pub fn min_rate(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.opts.min_rate = bytes_per_sec;
+        self
+    }
+
+    /// Grace period for [`min_rate`](Self::min_rate) (default 30 s).
+    pub fn min_rate_time(mut self, grace: Duration) -> Self {
+        self.opts.min_rate_time = grace;
+        self
+    }
+
+    /// Credentials for every request; these take precedence over any
+    /// `user:pass@` embedded in a URL.
+    pub fn auth(mut self, auth: Option<Auth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Send Basic credentials from `netrc` to hosts it has an entry for (or
+    /// to any host, given a `default` entry). [`auth`](Self::auth) and
+    /// `user@` in a URL take precedence.
+    pub fn netrc(mut self, netrc: Option<Netrc>) -> Self {
+        self.netrc = netrc;
+        self
+    }
+
+    /// Accept a server only if the SHA-256 of its public key (DER
+    /// `SubjectPublicKeyInfo`) or of its whole certificate is one of `pins`,
+    /// on top of the usual certificate checks; several allow for rotation.
+    /// Plain HTTP URLs then fail.
+    ///
+    /// This configures the client [`build`](Self::build) makes; one passed to
+    /// [`client`](Self::client) has to be built with [`pinned_tls_config`]
+    /// instead, or [`build`](Self::build) fails.
+    pub fn pin_sha256(mut self, pins: Vec<[u8; 32]>) -> Self {
+        self.pins = pins;
+        self
+    }
+
+    /// Send one of `agents`, picked at random, as the `User-Agent` of each
+    /// request, segments and probes included, in place of the client's.
+    /// Empty (the default) leaves the client's alone.
+    pub fn user_agents(mut self, agents: Vec<HeaderValue>) -> Self {
+        self.user_agents = agents;
+        self
+    }
+
+    /// Sign requests to S3 endpoints with these (AWS Signature Version 4),
+    /// and use their region for `s3://bucket/key` URLs. Presigned URLs and
+    /// requests already carrying [`auth`](Self::auth) are left alone.
+    #[cfg(feature = "s3")]
+    pub fn aws_credentials(mut self, creds: Option<AwsCredentials>) -> Self {
+        self.aws = creds;
+        self
+    }
+
+    /// Fetch files with this method instead of `GET`, e.g. for endpoints that
+    /// want a `POST`. Other methods are never resumed or segmented.
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Body to send with every download request; set its `Content-Type` as a
+    /// default header on the client.
+    pub fn body(mut self, body: Option<Bytes>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Fail any download whose `Content-Type` doesn't fit `patterns`:
+    /// `application/*` style globs it must match one of, `!text/html` ones it
+    /// must match none of. The failure isn't retried.
+    pub fn accept_types(mut self, patterns: Vec<String>) -> Self {
+        self.accept_types = patterns;
+        self
+    }
+
+    /// Cancel the rest of the batch as soon as one download fails for good.
+    pub fn fail_fast(mut self, on: bool) -> Self {
+        self.opts.fail_fast = on;
+        self
+    }
+
+    /// Only find out where each file would go and how big it is, with a
+    /// `HEAD` request, writing nothing; results come back as
+    /// [`Status::Planned`].
+    pub fn dry_run(mut self, on: bool) -> Self {
+        self.opts.dry_run = on;
+        self
+    }
+
+    /// Token that stops the batch when cancelled: transfers in flight stop at
+    /// the next chunk and keep their `.part` file, queued ones are skipped.
+    pub fn cancel_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Draw nothing: no progress bars and no per-file status lines.
+    pub fn quiet(mut self, on: bool) -> Self {
+        self.opts.quiet = on;
+        self
+    }
+
+    /// Draw progress bars into `multi` instead of a fresh one, so the caller
+    /// can print (or log) around them with [`MultiProgress::suspend`].
+    pub fn progress(mut self, multi: MultiProgress) -> Self {
+        self.multi = Some(multi);
+        self
+    }
+
+    /// Draw progress bars with colors; off gives plain bars for logs and
+    /// terminals that don't want escape codes.
+    pub fn color(mut self, on: bool) -> Self {
+        self.color = on;
+        self
+    }
+
+    /// Expected SHA-256 digests (hex), keyed by [`file_name_from_url`].
+    pub fn checksums(mut self, checksums: HashMap<String, String>) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Per-block SHA-256 digests, keyed by [`file_name_from_url`]. Such files
+    /// are fetched in ranges (even with one segment) that start on block
+    /// boundaries, and each block is checked as its range completes; a block
+    /// that doesn't match is fetched again on its own rather than the whole
+    /// file. Servers that don't take ranges get a plain download.
+    pub fn block_hashes(mut self, hashes: HashMap<String, BlockHashes>) -> Self {
+        self.block_hashes = hashes;
+        self
+    }
+
+    /// Alternate URLs for the same file, keyed by the URL passed to
+    /// [`Downloader::download`]. Each is tried in order once the one before it
+    /// has used up its retries or failed for good.
+    pub fn mirrors(mut self, mirrors: HashMap<Url, Vec<Url>>) -> Self {
+        self.mirrors = mirrors;
+        self
+    }
+
+    /// Record every URL's progress in `session` as the batch runs, and skip
+    /// the ones it already has down as done. See [`Session::open`].
+    pub fn session(mut self, session: Option<Session>) -> Self {
+        self.session = session.map(Arc::new);
+        self
+    }
+
+    /// Remember each saved file's `ETag` and `Last-Modified` in `cache`, and
+    /// on later runs ask for a URL whose copy is still there conditionally
+    /// (`If-None-Match`, `If-Modified-Since`). A `304` then leaves the copy
+    /// be, as [`Status::NotModified`]; any other answer is downloaded as
+    /// usual, so keeping a mirror in sync wants
+    /// [`OnConflict::Overwrite`] too. See [`HttpCache::open`].
+    pub fn http_cache(mut self, cache: Option<HttpCache>) -> Self {
+        self.cache = cache.map(Arc::new);
+        self
+    }
+
+    /// Report every transfer's state and progress to `monitor`, which can
+    /// also cancel them one at a time.
+    pub fn monitor(mut self, monitor: Arc<Monitor>) -> Self {
+        self.monitor = Some(monitor);
+        self
+    }
+
+    /// Count files and bytes into `stats` as well, for a caller that wants
+    /// running totals without following every transfer.
+    pub fn stats(mut self, stats: Arc<BatchStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Append a `<digest>  <path>` line for every saved file to `manifest`, in
+    /// `sha256sum` format with paths relative to the output directory. The
+    /// digest is taken as the file streams in.
+    pub fn write_checksums(mut self, manifest: Option<PathBuf>) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    /// Digest algorithm for [`write_checksums`](Self::write_checksums) and
+    /// [`dedup_by_hash`](Self::dedup_by_hash).
+    pub fn hash(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash = algorithm;
+        self
+    }
+
+    /// Turn each saved file whose digest matches an earlier one's into a
+    /// hard link to it, or a symlink where hard links fail. A file that
+    /// can't be linked either way stays a copy.
+    pub fn dedup_by_hash(mut self, on: bool) -> Self {
+        self.dedup = on;
+        self
+    }
+
+    /// Run `command` through the shell after each file is saved, `{}` in it
+    /// standing for the file's path (which is appended when there's no `{}`).
+    /// It runs in the file's download slot, and its output is shown once it
+    /// exits; a failure is reported in [`DownloadResult::exec_error`] but
+    /// leaves the download saved.
+    pub fn exec(mut self, command: Option<String>) -> Self {
+        self.exec = command;
+        self
+    }
+
+    /// Like [`exec`](Self::exec), for each file that failed to download,
+    /// e.g. to clean up; `{}` is the path it would have been saved to.
+    pub fn exec_on_failure(mut self, command: Option<String>) -> Self {
+        self.exec_on_failure = command;
+        self
+    }
+
+    /// Finish the [`Downloader`]. Fails only with
+    /// [`pin_sha256`](Self::pin_sha256): if a client was given too, or if
+    /// the pinned client can't be set up, as when no CA certificates can be
+    /// found.
+    pub fn build(mut self) -> anyhow::Result<Downloader> {
+        // Every bar goes through one MultiProgress so concurrent downloads
+        // don't draw over each other; clones share the same terminal state
+        let multi = match self.multi {
+            Some(multi) => multi,
+            None if self.opts.quiet => {
+                MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+            }
+            None => MultiProgress::new(),
+        };
+        self.opts.digest = (self.manifest.is_some() || self.dedup).then_some(self.hash);
+        let client = match self.client {
+            Some(_) if !self.pins.is_empty() => {
+                return Err(anyhow!(
+                    "pin_sha256 only applies to the default client; \
+                     build the given one with pinned_tls_config"
+                ));
+            }
+            Some(client) => client,
+            None if !self.pins.is_empty() => {
+                let tls = pinned_tls_config(&self.pins, Vec::new(), None, true)?;
+                reqwest::Client::builder()
+                    .https_only(true)
+                    .use_preconfigured_tls(tls)
+                    .build()
+                    .context("build pinned HTTP client")?
+            }
+            None => reqwest::Client::default(),
+        };
+        Ok(Downloader {
+            shared: Shared {
+                client,
+                multi,
+                limiter: self.max_rate.map(|r| Arc::new(RateLimiter::new(r))),
+                file_limiter: None,
+                auth: self.auth,
+                netrc: self.netrc.map(Arc::new),
+                user_agents: Arc::new(self.user_agents),
+                #[cfg(feature = "s3")]
+                aws: self.aws.map(Arc::new),
+                method: self.method,
+                body: self.body,
+                accept: Arc::new(TypeFilter::new(&self.accept_types)),
+                batch_cancel: self.cancel.clone(),
+                cancel: self.cancel,
+                reserved: Arc::default(),
+                pending_bytes: Arc::default(),
+                failures: Arc::default(),
+                color: self.color,
+                batch: BatchProgress::hidden(self.stats.clone()),
+                headers: None,
+                timeout: None,
+                tracker: None,
+                validators: None,
+            },
+            out_dir: self.out_dir,
+            concurrency: self.concurrency,
+            auto_concurrency: self.auto_concurrency,
+            per_host: self.per_host,
+            template: self.template.map(Arc::new),
+            output_file: self.output_file,
+            checksums: Arc::new(self.checksums),
+            block_hashes: Arc::new(self.block_hashes),
+            mirrors: Arc::new(self.mirrors),
+            manifest: self.manifest.map(|path| Arc::new(Manifest::new(path))),
+            dedup: self.dedup.then(Arc::default),
+            exec: self.exec.map(Arc::from),
+            exec_on_failure: self.exec_on_failure.map(Arc::from),
+            session: self.session,
+            cache: self.cache,
+            monitor: self.monitor,
+            stats: self.stats,
+            opts: self.opts,
+        })
+    }
+}
+
+/// Downloads batches of URLs; see the [crate docs](crate) for an example.
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    shared: Shared,
+    out_dir: PathBuf,
+    concurrency: usize,
+    auto_concurrency: Option<usize>,
+    per_host: Option<usize>,
+    template: Option<Arc<OutputTemplate>>,
+    output_file: Option<PathBuf>,
+    checksums: Arc<HashMap<String, String>>,
+    block_hashes: Arc<HashMap<String, BlockHashes>>,
+    mirrors: Arc<HashMap<Url, Vec<Url>>>,
+    manifest: Option<Arc<Manifest>>,
+    dedup: Option<Arc<Dedup>>,
+    exec: Option<Arc<str>>,
+    exec_on_failure: Option<Arc<str>>,
+    session: Option<Arc<Session>>,
+    cache: Option<Arc<HttpCache>>,
+    monitor: Option<Arc<Monitor>>,
+    stats: Arc<BatchStats>,
+    opts: Options,
+}
+
+impl Downloader {
+    pub fn builder() -> DownloaderBuilder {
+        DownloaderBuilder::default()
+    }
+
+    /// Download every URL, at most `concurrency` at a time (or as many as
+    /// auto-concurrency allows), and at most `per_host` per host when set.
+    ///
+    /// A URL given more than once is fetched once. When different URLs map to
+    /// the same file name, later ones get the `name (1).ext` variants, in
+    /// order.
+    ///
+    /// Results come back in the same order as `urls`. Progress bars and a
+    /// `saved ->` / `FAILED` line per file are drawn as downloads finish,
+    /// under a totals bar when there's more than one file.
+    pub async fn download(&self, urls: &[Url]) -> Vec<DownloadResult> {
+        let specs: Vec<DownloadSpec> = urls.iter().cloned().map(DownloadSpec::new).collect();
+        self.download_specs(&specs).await
+    }
+
+    /// Fetch `url` into memory instead of a file, with the same retries,
+    /// timeouts, credentials and rate limits as a download. The
+    /// [`max_size`](DownloaderBuilder::max_size) limit, checked as the body
+    /// arrives, keeps a huge or endless one from exhausting memory; without
+    /// it there's no bound. Nothing is written to disk or drawn, not even a
+    /// line about a retry.
+    pub async fn download_to_bytes(&self, url: &Url) -> Result<Vec<u8>, DownloadError> {
+        if local::is_local(url) {
+            return local::read(url, self.opts).await;
+        }
+        let opts = Options {
+            quiet: true,
+            ..self.opts
+        };
+        let mut shared = self.shared.clone();
+        shared.file_limiter = opts
+            .max_rate_per_file
+            .map(|r| Arc::new(RateLimiter::new(r)));
+        let shared = &shared;
+        let (res, attempts) =
+            with_retries(shared, url, opts, || fetch_bytes(shared, url, opts)).await;
+        info!(%url, attempts, ok = res.is_ok(), "fetched into memory");
+        res
+    }
+
+    /// [`download`](Self::download) with settings that differ from file to
+    /// file; see [`DownloadSpec`]. When a URL appears more than once, its
+    /// first spec is the one used.
+    pub async fn download_specs(&self, specs: &[DownloadSpec]) -> Vec<DownloadResult> {
+        let unique = specs.iter().map(|s| &s.url).collect::<HashSet<_>>().len();
+        let progress = if unique > 1 && !self.opts.quiet {
+            BatchProgress::new(
+                &self.shared.multi,
+                unique as u64,
+                self.shared.color,
+                self.stats.clone(),
+            )
+        } else {
+            BatchProgress::hidden(self.stats.clone())
+        };
+        self.stats.add_files(unique as u64);
+        // Bounded parallelism: one global pool, plus one pool per host
+        let (sem, tuner) = match self.auto_concurrency {
+            Some(max) => {
+                let sem = Arc::new(Semaphore::new(autotune::START.min(max)));
+                let failures = self.shared.failures.clone();
+                let tuner = autotune::run(sem.clone(), max, progress.clone(), failures);
+                (sem, Some(tokio::spawn(tuner)))
+            }
+            None => (Arc::new(Semaphore::new(self.concurrency)), None),
+        };
+        let mut host_sems: HashMap<String, Arc<Semaphore>> = HashMap::new();
+        let mut handles = Vec::new();
+        // Fail-fast stops this batch only, not the caller's token
+        let batch = self.shared.cancel.child_token();
+        let deadline = self.opts.deadline.map(|limit| {
+            let (batch, multi, quiet) = (batch.clone(), self.shared.multi.clone(), self.opts.quiet);
+            tokio::spawn(async move {
+                tokio::time::sleep(limit).await;
+                info!(?limit, "deadline reached");
+                if !quiet {
+                    let limit = humantime::format_duration(limit);
+                    multi.suspend(|| eprintln!("deadline of {limit} reached; stopping"));
+                }
+                batch.cancel();
+            })
+        });
+        let mut seen = HashSet::new();
+        let reserved = Arc::new(Mutex::new(HashSet::new()));
+
+        let mut planned = Vec::new();
+        for (index, spec) in specs.iter().enumerate() {
+            let url = &spec.url;
+            // Two tasks for one URL would race on the same .part file
+            if !seen.insert(url) {
+                continue;
+            }
+            if let Some(name) = spec.output.as_ref().or(self.output_file.as_ref()) {
+                let path = self.out_dir.join(name);
+                reserved.lock().unwrap().insert(path.clone());
+                planned.push((spec.clone(), path, false));
+                continue;
+            }
+            // A resumed session keeps its paths, so .part files line up again
+            if let Some((_, path)) = self.session.as_ref().and_then(|s| s.get(url)) {
+                reserved.lock().unwrap().insert(path.clone());
+                planned.push((spec.clone(), path, false));
+                continue;
+            }
+            // Names are handed out here, in URL order, so which of two
+            // colliding URLs gets the `(1)` doesn't depend on task timing.
+            // Names from response headers are reserved from the same set later.
+            // Timestamping and skip-existing compare against, and replace,
+            // the existing file
+            let (dir, mut name) = self.planned_name(url, index + 1);
+            // A name given for this URL alone takes the place of the one
+            // from its path, clashes and all
+            if let Some(given) = spec.name.as_deref().and_then(sanitize_file_name) {
+                name = given;
+            }
+            let keep = self.opts.on_conflict.keeps_name() || self.opts.timestamping;
+            let (path, taken) = match self.opts.number_by {
+                NumberBy::UrlOrder => (reserve_output_path(&reserved, &dir, &name, keep), false),
+                NumberBy::None => match reserve_unnumbered(&reserved, &dir, &name, keep) {
+                    Some(path) => (path, false),
+                    None => (dir.join(&name), true),
+                },
+            };
+            planned.push((spec.clone(), path, taken));
+        }
+        if let Some(session) = &self.session {
+            let urls: Vec<_> = planned
+                .iter()
+                .filter(|(_, _, taken)| !taken)
+                .map(|(s, p, _)| (s.url.clone(), p.clone()))
+                .collect();
+            if let Err(e) = session.add(&urls) {
+                self.shared.multi.suspend(|| eprintln!("warning: {e}"));
+            }
+        }
+
+        for (spec, path, taken) in planned {
+            let url = spec.url.clone();
+            let task = (url.clone(), path.clone(), taken);
+            let host_sem = self.per_host.map(|n| {
+                let host = url.host_str().unwrap_or_default().to_string();
+                host_sems
+                    .entry(host)
+                    .or_insert_with(|| Arc::new(Semaphore::new(n)))
+                    .clone()
+            });
+            let sem = sem.clone();
+            let mut this = self.clone();
+            this.shared.cancel = batch.clone();
+            this.shared.batch_cancel = batch.clone();
+            if let Some(monitor) = &self.monitor {
+                // Its own token, so the monitor can stop this one alone
+                this.shared.cancel = batch.child_token();
+                let tracker = monitor.add(url.clone(), path.clone(), this.shared.cancel.clone());
+                this.shared.tracker = Some(tracker);
+            }
+            this.shared.reserved = reserved.clone();
+            this.shared.batch = progress.clone();
+            this.shared.file_limiter = self
+                .opts
+                .max_rate_per_file
+                .map(|r| Arc::new(RateLimiter::new(r)));
+            if !spec.headers.is_empty() {
+                this.shared.headers = Some(Arc::new(spec.headers.clone()));
+            }
+            if spec.output.is_some() || spec.name.is_some() {
+                this.opts.fixed_name = true;
+            }
+            this.opts.max_size = spec.max_size.or(self.opts.max_size);
+            this.opts.retries = spec.retries.unwrap_or(self.opts.retries);
+            this.opts.backoff_ms = spec.backoff_ms.unwrap_or(self.opts.backoff_ms);
+            this.shared.timeout = spec.timeout;
+            this.shared.validators = self
+                .cache
+                .as_ref()
+                .and_then(|c| c.get(&url))
+                .map(|(_, v)| Arc::new(v));
+            let weight = match self.auto_concurrency {
+                Some(_) => 1,
+                None => spec.weight.clamp(1, self.concurrency.max(1)) as u32,
+            };
+
+            // Kept out here too, to settle the task if it panics
+            let tracker = this.shared.tracker.clone();
+            let h = tokio::spawn(async move {
+                let res = async {
+                    if taken {
+                        let e = DownloadError::NameTaken { path: path.clone() };
+                        if !this.opts.quiet {
+                            this.shared.multi.suspend(|| eprintln!("FAILED {url}: {e}"));
+                        }
+                        let mut r = DownloadResult::new(url, Status::Failed, path);
+                        r.error = Some(e);
+                        return r;
+                    }
+                    if this.already_done(&url, &path) {
+                        return DownloadResult::new(url, Status::UpToDate, path);
+                    }
+                    // Take the host slot first so a crowded host's queue doesn't
+                    // sit on global slots that other hosts could use
+                    let permits = async {
+                        let host = match host_sem {
+                            Some(s) => Some(s.acquire_owned().await?),
+                            None => None,
+                        };
+                        Ok::<_, AcquireError>((host, sem.acquire_many_owned(weight).await?))
+                    };
+                    let _permits = tokio::select! {
+                        biased;
+                        // Cancellation stopped the queue before this got a slot
+                        _ = this.shared.cancel.cancelled() => {
+                            return DownloadResult::new(url, Status::Skipped, path);
+                        }
+                        permits = permits => match permits {
+                            Ok(permits) => permits, // keep the slots until the task finishes
+                            // A closed pool hands out no more slots, as good as cancelled
+                            Err(_) => return DownloadResult::new(url, Status::Skipped, path),
+                        },
+                    };
+                    if let Some(limit) = this.opts.max_total_size {
+                        if this.stats.bytes() >= limit {
+                            let e = DownloadError::OverBudget { limit };
+                            if !this.opts.quiet {
+                                this.shared
+                                    .multi
+                                    .suspend(|| eprintln!("skipped {url}: {e}"));
+                            }
+                            let mut r = DownloadResult::new(url, Status::Skipped, path);
+                            r.error = Some(e);
+                            return r;
+                        }
+                    }
+                    this.record(|s| s.start(&url, &path));
+                    if let Some(t) = &this.shared.tracker {
+                        t.start();
+                    }
+                    this.fetch(spec, path).await
+                }
+                .await;
+                // A name clash never made it into the session
+                if !taken {
+                    this.record(|s| s.finish(&res));
+                }
+                if let Some(t) = &this.shared.tracker {
+                    t.finish(&res);
+                }
+                this.shared.batch.file_done();
+                res
+            });
+
+            handles.push((task, tracker, h));
+        }
+
+        let mut results = Vec::with_capacity(handles.len());
+        for ((url, path, taken), tracker, h) in handles {
+            // Tasks report their own errors; a panic leaves that to us
+            let res = match h.await {
+                Ok(res) => res,
+                Err(e) => {
+                    let e = DownloadError::Panicked(panic_message(e));
+                    info!(%url, error = %e, "panicked");
+                    if !self.opts.quiet {
+                        self.shared.multi.suspend(|| eprintln!("FAILED {url}: {e}"));
+                    }
+                    let mut r = DownloadResult::new(url, Status::Failed, path);
+                    r.error = Some(e);
+                    if !taken {
+                        self.record(|s| s.finish(&r));
+                    }
+                    if let Some(t) = &tracker {
+                        t.finish(&r);
+                    }
+                    progress.file_done();
+                    r
+                }
+            };
+            results.push(res);
+        }
+        if let Some(tuner) = tuner {
+            tuner.abort();
+        }
+        if let Some(deadline) = deadline {
+            deadline.abort();
+        }
+        progress.finish(self.opts.clear_finished);
+        results
+    }
+
+    /// Whether the session says an earlier run finished `url` into `path`,
+    /// and the file is still there.
+    fn already_done(&self, url: &Url, path: &Path) -> bool {
+        let done = matches!(
+            self.session.as_ref().and_then(|s| s.get(url)),
+            Some((session::State::Done, _))
+        );
+        if !done || !path.exists() {
+            return false;
+        }
+        if !self.opts.quiet {
+            self.shared
+                .multi
+                .suspend(|| println!("done -> {}", path.display()));
+        }
+        true
+    }
+
+    /// Update the session, if there is one; failing to is only worth a warning.
+    fn record(&self, update: impl FnOnce(&Session) -> Result<()>) {
+        if let Some(Err(e)) = self.session.as_deref().map(update) {
+            self.shared.multi.suspend(|| eprintln!("warning: {e}"));
+        }
+    }
+
+    /// Directory and file name for `url` before any `(N)` suffix: from the
+    /// output template if there is one, else the URL's last segment in the
+    /// output directory (or a subdirectory mirroring the URL path).
+    fn planned_name(&self, url: &Url, index: usize) -> (PathBuf, String) {
+        if let Some(template) = &self.template {
+            let rendered = template.render(url, index);
+            let name = rendered.file_name().unwrap_or_default();
+            let dir = rendered.parent().unwrap_or(Path::new(""));
+            return (self.out_dir.join(dir), name.to_string_lossy().into_owned());
+        }
+        let dir = if self.opts.preserve_path {
+            self.out_dir.join(url_dirs(url, self.opts.cut_dirs))
+        } else {
+            self.out_dir.clone()
+        };
+        (dir, saved_name_from_url(url))
+    }
+
+    /// Fetch `source`, the URL itself or one of its mirrors, into `path`.
+    async fn fetch_from(
+        &self,
+        source: &Url,
+        path: &Path,
+        expected: Option<&str>,
+        blocks: Option<&BlockHashes>,
+    ) -> (Result<Saved>, u32) {
+        let (shared, opts) = (&self.shared, self.opts);
+        if local::is_local(source) {
+            (local::fetch(shared, source, path, opts, expected).await, 1)
+        } else if opts.segments > 1 || blocks.is_some() {
+            segmented::download(shared, source, path, opts, expected, blocks).await
+        } else {
+            download_with_retries(shared, source, path, opts, expected).await
+        }
+    }
+
+    /// Unpack `saved` next to itself if it's an archive, returning where to.
+    async fn unpack(&self, saved: &Saved) -> Result<Option<PathBuf>> {
+        let Some(kind) = Archive::detect(&saved.path, saved.content_type.as_deref()) else {
+            return Ok(None);
+        };
+        let path = saved.path.clone();
+        let dir = extract::target_dir(&path);
+        let files = tokio::task::spawn_blocking({
+            let (path, dir) = (path.clone(), dir.clone());
+            move || extract::extract(&path, kind, &dir)
+        })
+        .await
+        .map_err(|e| DownloadError::Extract {
+            path: path.clone(),
+            reason: format!("panicked: {}", panic_message(e)),
+        })??;
+        info!(path = %path.display(), dir = %dir.display(), files, "extracted");
+        if self.opts.remove_archive {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                self.shared
+                    .multi
+                    .suspend(|| eprintln!("warning: remove {}: {e}", path.display()));
+            }
+        }
+        Ok(Some(dir))
+    }
+
+    /// With deduplication on, make `saved` a link to an earlier file with the
+    /// same digest, returning that file and the kind of link.
+    async fn link_duplicate(&self, saved: &Saved) -> Option<(PathBuf, Link)> {
+        let seen = self.dedup.as_ref()?;
+        let original = seen.original(saved.digest.as_deref()?, &saved.path)?;
+        match dedup::link(&original, &saved.path).await {
+            Ok(kind) => {
+                info!(
+                    path = %saved.path.display(),
+                    same_as = %original.display(),
+                    ?kind,
+                    "linked duplicate"
+                );
+                Some((original, kind))
+            }
+            Err(e) => {
+                self.shared.multi.suspend(|| {
+                    eprintln!(
+                        "warning: can't link {} to {}, keeping a copy: {e}",
+                        saved.path.display(),
+                        original.display()
+                    )
+                });
+                None
+            }
+        }
+    }
+
+    /// Result for a file a size range left out, `e` saying how big it is.
+    fn filtered(&self, url: Url, path: PathBuf, e: DownloadError) -> DownloadResult {
+        info!(%url, reason = %e, "outside size range");
+        if !self.opts.quiet {
+            self.shared
+                .multi
+                .suspend(|| println!("filtered {url}: {e}"));
+        }
+        let mut r = DownloadResult::new(url, Status::Filtered, path);
+        if let DownloadError::OutsideSizeRange { size, .. } = e {
+            r.size = Some(size);
+        }
+        r.error = Some(e);
+        r
+    }
+
+    /// Dry-run stand-in for [`fetch`](Self::fetch): report where `url`
+    /// would be saved without touching the disk.
+    async fn plan(&self, url: Url, path: PathBuf) -> DownloadResult {
+        let (shared, opts) = (&self.shared, self.opts);
+        let (probed, attempts) = if local::is_local(&url) {
+            let size = local::size(&url).await.map(|size| Probed {
+                path: path.clone(),
+                size: Some(size),
+                final_url: url.clone(),
+                content_type: None,
+                last_modified: None,
+                accept_ranges: None,
+            });
+            (size, 1)
+        } else {
+            with_retries(shared, &url, opts, || probe(shared, &url, &path, opts)).await
+        };
+        let mut result = match probed {
+            Ok(p) => {
+                if !opts.quiet {
+                    let size = match p.size {
+                        Some(n) => HumanBytes(n).to_string(),
+                        None => "unknown size".to_string(),
+                    };
+                    shared
+                        .multi
+                        .suspend(|| println!("would save -> {} ({size})", p.path.display()));
+                }
+                let mut r = DownloadResult::new(url.clone(), Status::Planned, p.path);
+                r.size = p.size;
+                r.content_type = p.content_type;
+                r.last_modified = p.last_modified;
+                r.accept_ranges = p.accept_ranges;
+                r.final_url = (p.final_url != url).then_some(p.final_url);
+                r
+            }
+            Err(e @ DownloadError::OutsideSizeRange { .. }) => self.filtered(url, path, e),
+            Err(e) => {
+                if !opts.quiet {
+                    shared.multi.suspend(|| eprintln!("unreachable {url}: {e}"));
+                }
+                let mut r = DownloadResult::new(url, Status::Failed, path);
+                r.error = Some(e);
+                r
+            }
+        };
+        result.attempts = attempts;
+        result
+    }
+
+    /// Everything that happens to one URL once it holds its slots.
+    async fn fetch(&self, spec: DownloadSpec, path: PathBuf) -> DownloadResult {
+        let (shared, opts) = (&self.shared, self.opts);
+        let url = spec.url;
+        if opts.dry_run {
+            return self.plan(url, path).await;
+        }
+        let fname = file_name_from_url(&url);
+        let dir = path.parent().unwrap_or(&self.out_dir);
+        let made = if opts.create_dirs {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(DownloadError::io(format!("create {}", dir.display())))
+        } else if dir.is_dir() {
+            Ok(())
+        } else {
+            Err(DownloadError::MissingDir {
+                path: dir.to_path_buf(),
+            })
+        };
+        if let Err(e) = made {
+            if !opts.quiet {
+                shared.multi.suspend(|| eprintln!("FAILED {url}: {e}"));
+            }
+            let mut r = DownloadResult::new(url, Status::Failed, path.clone());
+            r.error = Some(e);
+            return r;
+        }
+        let expected = spec
+            .sha256
+            .as_deref()
+            .or_else(|| self.checksums.get(&fname).map(String::as_str));
+        let blocks = self.block_hashes.get(&fname);
+        info!(%url, path = %path.display(), sha256 = expected, "starting");
+
+        if opts.timestamping && !local::is_local(&url) && is_up_to_date(shared, &url, &path).await {
+            if !opts.quiet {
+                shared
+                    .multi
+                    .suspend(|| println!("up-to-date -> {}", path.display()));
+            }
+            return DownloadResult::new(url, Status::UpToDate, path);
+        }
+        let exists = match opts.on_conflict {
+            OnConflict::Skip => path.exists(),
+            OnConflict::Resume => {
+                !local::is_local(&url) && is_complete(shared, &url, &path, opts).await
+            }
+            OnConflict::Error if path.exists() => {
+                let e = DownloadError::Exists { path: path.clone() };
+                if !opts.quiet {
+                    shared.multi.suspend(|| eprintln!("FAILED {url}: {e}"));
+                }
+                let mut r = DownloadResult::new(url, Status::Failed, path);
+                r.error = Some(e);
+                return r;
+            }
+            _ => false,
+        };
+        if exists {
+            if !opts.quiet {
+                shared
+                    .multi
+                    .suspend(|| println!("exists -> {}", path.display()));
+            }
+            return DownloadResult::new(url, Status::UpToDate, path);
+        }
+
+        let (mut res, mut attempts) = self.fetch_from(&url, &path, expected, blocks).await;
+        let mut source = &url;
+        let mirrors = match spec.mirrors.as_slice() {
+            [] => self.mirrors.get(&url).map_or(&[][..], Vec::as_slice),
+            own => own,
+        };
+        for mirror in mirrors {
+            match &res {
+                Ok(_)
+                | Err(
+                    DownloadError::Interrupted
+                    | DownloadError::OverBudget { .. }
+                    | DownloadError::NotModified
+                    | DownloadError::OutsideSizeRange { .. },
+                ) => break,
+                Err(e) if !opts.quiet => shared
+                    .multi
+                    .suspend(|| eprintln!("{source} failed ({e}); trying mirror {mirror}")),
+                Err(_) => {}
+            }
+            let (r, a) = self.fetch_from(mirror, &path, expected, blocks).await;
+            (res, source) = (r, mirror);
+            attempts += a;
+        }
+        let mirror = (source != &url).then(|| source.clone());
+        let same_as = match &res {
+            Ok(saved) => self.link_duplicate(saved).await,
+            Err(_) => None,
+        };
+        // Unpacking is part of the job: an archive that won't extract fails it
+        let (res, extracted) = match res {
+            Ok(saved) if opts.extract => match self.unpack(&saved).await {
+                Ok(dir) => (Ok(saved), dir),
+                Err(e) => (Err(e), None),
+            },
+            res => (res, None),
+        };
+        let mut result = match res {
+            Ok(saved) => {
+                let redirected = (saved.final_url != url).then_some(saved.final_url);
+                if !opts.quiet {
+                    let via = match &redirected {
+                        Some(u) => format!(" (from {u})"),
+                        None => String::new(),
+                    };
+                    shared
+                        .multi
+                        .suspend(|| println!("saved -> {}{via}", saved.path.display()));
+                    if let Some((original, kind)) = &same_as {
+                        let kind = match kind {
+                            Link::Hard => "hard link",
+                            Link::Symbolic => "symlink",
+                        };
+                        shared.multi.suspend(|| {
+                            println!("linked -> {} (same content; {kind})", original.display())
+                        });
+                    }
+                    if let Some(dir) = &extracted {
+                        shared
+                            .multi
+                            .suspend(|| println!("extracted -> {}", dir.display()));
+                    }
+                }
+                if let (Some(manifest), Some(digest)) = (&self.manifest, &saved.digest) {
+                    let name = saved
+                        .path
+                        .strip_prefix(&self.out_dir)
+                        .unwrap_or(&saved.path);
+                    if let Err(e) = manifest.append(digest, name) {
+                        shared.multi.suspend(|| eprintln!("warning: {e}"));
+                    }
+                }
+                if let Some(cache) = &self.cache {
+                    if let Err(e) = cache.store(&url, &saved.path, saved.validators) {
+                        shared.multi.suspend(|| eprintln!("warning: {e}"));
+                    }
+                }
+                if let Some(response) = &saved.response {
+                    if let Err(e) = save_headers(&saved.path, response).await {
+                        shared.multi.suspend(|| eprintln!("warning: {e}"));
+                    }
+                }
+                info!(
+                    %url,
+                    path = %saved.path.display(),
+                    bytes = saved.bytes,
+                    mirror = mirror.as_ref().map(Url::as_str),
+                    "saved"
+                );
+                let mut r = DownloadResult::new(url, Status::Saved, saved.path);
+                r.bytes = saved.bytes;
+                r.final_url = redirected;
+                r.mirror = mirror;
+                r.digest = saved.digest;
+                r.same_as = same_as.map(|(original, _)| original);
+                r.extracted = extracted;
+                r.ttfb = saved.ttfb;
+                r.elapsed = Some(saved.elapsed);
+                r.segments = saved.segments;
+                r
+            }
+            Err(DownloadError::NotModified) => {
+                // The copy that was checked, wherever this run would have put a new one
+                let path = self
+                    .cache
+                    .as_ref()
+                    .and_then(|c| c.get(&url))
+                    .map_or(path, |(cached, _)| cached);
+                info!(%url, path = %path.display(), "not modified");
+                if !opts.quiet {
+                    shared
+                        .multi
+                        .suspend(|| println!("not-modified -> {}", path.display()));
+                }
+                DownloadResult::new(url, Status::NotModified, path)
+            }
+            Err(e @ DownloadError::OutsideSizeRange { .. }) => self.filtered(url, path, e),
+            Err(e @ DownloadError::Interrupted) => {
+                info!(%url, attempts, "interrupted");
+                if !opts.quiet {
+                    shared.multi.suspend(|| eprintln!("interrupted {url}"));
+                }
+                let mut r = DownloadResult::new(url, Status::Interrupted, path);
+                r.error = Some(e);
+                r
+            }
+            Err(e @ DownloadError::OverBudget { .. }) => {
+                info!(%url, attempts, "over total size limit");
+                if !opts.quiet {
+                    shared.multi.suspend(|| eprintln!("aborted {url}: {e}"));
+                }
+                let mut r = DownloadResult::new(url, Status::Interrupted, path);
+                r.error = Some(e);
+                r
+            }
+            Err(e) => {
+                info!(%url, attempts, error = e.chain(), "failed");
+                if !opts.quiet {
+                    let note = if e.is_retryable() {
+                        ""
+                    } else {
+                        " (not retryable)"
+                    };
+                    shared
+                        .multi
+                        .suspend(|| eprintln!("FAILED{note} {}: {e}", url));
+                }
+                if opts.fail_fast && !shared.batch_cancel.is_cancelled() {
+                    if !opts.quiet {
+                        shared
+                            .multi
+                            .suspend(|| eprintln!("fail-fast: cancelling remaining downloads"));
+                    }
+                    shared.batch_cancel.cancel();
+                }
+                let mut r = DownloadResult::new(url, Status::Failed, path);
+                r.error = Some(e);
+                r
+            }
+        };
+        result.attempts = attempts;
+        let hook = match result.status {
+            Status::Saved => self.exec.as_deref(),
+            Status::Failed => self.exec_on_failure.as_deref(),
+            _ => None,
+        };
+        if let Some(command) = hook {
+            if let Err(e) = hook::run(shared, command, &result.path).await {
+                info!(url = %result.url, error = e, "hook failed");
+                if !opts.quiet {
+                    shared
+                        .multi
+                        .suspend(|| eprintln!("exec failed for {}: {e}", result.path.display()));
+                }
+                result.exec_error = Some(e);
+            }
+        }
+        result
+    }
+}
+
+/// What a download or extraction task that never returned died of.
+fn panic_message(e: JoinError) -> String {
+    match e.try_into_panic() {
+        Ok(payload) => match payload.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(msg) => msg.to_string(),
+                Err(_) => "unknown panic".to_string(),
+            },
+        },
+        Err(e) => e.to_string(),
+    }
+}
@@ -0,0 +1,258 @@
+//! `file://` and `data:` URLs, which never touch the network.
+
+use base64::Engine;
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::time::Instant;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+};
+use url::Url;
+
+use crate::cache::Validators;
+use crate::download::{
+    check_remote_size, check_size, count_received, finish, part_path, progress_bar, to_hex,
+    verify_sha256, Saved,
+};
+use crate::error::{DownloadError, Result};
+use crate::hash::Hasher;
+use crate::naming::{extension_for_mime, reserve_renamed};
+use crate::space::SpaceClaim;
+use crate::{Options, Shared};
+
+/// Copy the file behind a `file://` URL, with a progress bar, through the
+/// usual `.part` file.
+async fn copy_file(
+    shared: &Shared,
+    url: &Url,
+    path: &Path,
+    opts: Options,
+    expected_sha256: Option<&str>,
+) -> Result<Saved> {
+    let src = url.to_file_path().map_err(|_| DownloadError::InvalidUrl {
+        url: url.clone(),
+        reason: "not a local file path".to_string(),
+    })?;
+    let mut input = fs::File::open(&src)
+        .await
+        .map_err(DownloadError::io(format!("open {}", src.display())))?;
+    let meta = input
+        .metadata()
+        .await
+        .map_err(DownloadError::io(format!("stat {}", src.display())))?;
+    check_remote_size(meta.len(), opts)?;
+    check_size(meta.len(), opts)?;
+
+    let part = part_path(path);
+    let _claim = SpaceClaim::new(shared, &part, meta.len())?;
+    let started = Instant::now();
+    let res = async {
+        let pb = progress_bar(shared, path, Some(meta.len()), opts);
+        let output = fs::File::create(&part)
+            .await
+            .map_err(DownloadError::io(format!("create file {}", part.display())))?;
+        let mut output = BufWriter::with_capacity(opts.buffer_size, output);
+        let write_err = || DownloadError::io(format!("write {}", part.display()));
+        let mut hasher = expected_sha256.map(|_| Sha256::new());
+        let mut digest = opts.digest.map(Hasher::new);
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut copied = 0u64;
+        loop {
+            if shared.cancel.is_cancelled() {
+                return Err(DownloadError::Interrupted);
+            }
+            let n = input
+                .read(&mut buf)
+                .await
+                .map_err(DownloadError::io(format!("read {}", src.display())))?;
+            if n == 0 {
+                break;
+            }
+            output.write_all(&buf[..n]).await.map_err(write_err())?;
+            if let Some(h) = hasher.as_mut() {
+                h.update(&buf[..n]);
+            }
+            if let Some(d) = digest.as_mut() {
+                d.update(&buf[..n]);
+            }
+            copied += n as u64;
+            pb.inc(n as u64);
+            count_received(shared, opts, n as u64)?;
+        }
+        output.flush().await.map_err(write_err())?;
+        drop(output);
+
+        if let (Some(h), Some(expected)) = (hasher, expected_sha256) {
+            verify_sha256(h, expected, &part, &pb).await?;
+        }
+        finish(&part, path, meta.modified().ok(), opts, &pb).await?;
+        Ok(Saved {
+            path: path.to_path_buf(),
+            bytes: copied,
+            final_url: url.clone(),
+            digest: digest.map(Hasher::finalize_hex),
+            content_type: None,
+            ttfb: None,
+            elapsed: started.elapsed(),
+            validators: Validators::default(),
+            response: None,
+            segments: Vec::new(),
+        })
+    }
+    .await;
+
+    // A local copy is cheap to redo, so there's nothing worth resuming
+    if res.is_err() {
+        let _ = fs::remove_file(&part).await;
+    }
+    res
+}
+
+/// Decode a `data:` URL and write its payload. With `adjust_extension` the
+/// file name takes an extension from the declared media type.
+async fn save_data(
+    shared: &Shared,
+    url: &Url,
+    path: &Path,
+    opts: Options,
+    expected_sha256: Option<&str>,
+) -> Result<Saved> {
+    let started = Instant::now();
+    let (mime, payload) = decode_data_url(url)?;
+    check_size(payload.len() as u64, opts)?;
+    let dest = match extension_for_mime(&mime) {
+        Some(ext) if opts.adjust_extension && !opts.fixed_name && path.extension().is_none() => {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            reserve_renamed(
+                &shared.reserved,
+                path,
+                &format!("{name}.{ext}"),
+                opts.on_conflict.keeps_name(),
+                opts.number_by,
+            )
+        }
+        _ => path.to_path_buf(),
+    };
+
+    if let Some(expected) = expected_sha256 {
+        let actual = to_hex(&Sha256::digest(&payload));
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(DownloadError::ChecksumMismatch {
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+    if let Err(e) = fs::write(&dest, &payload).await {
+        // No .part file here, so a failed write would leave a truncated file
+        let _ = fs::remove_file(&dest).await;
+        return Err(DownloadError::io(format!("write {}", dest.display()))(e));
+    }
+    let digest = opts.digest.map(|alg| {
+        let mut d = Hasher::new(alg);
+        d.update(&payload);
+        d.finalize_hex()
+    });
+    Ok(Saved {
+        path: dest,
+        bytes: payload.len() as u64,
+        final_url: url.clone(),
+        digest,
+        content_type: Some(mime),
+        ttfb: None,
+        elapsed: started.elapsed(),
+        validators: Validators::default(),
+        response: None,
+        segments: Vec::new(),
+    })
+}
+
+/// Split `data:[<mediatype>][;base64],<data>` into its media type and bytes.
+fn decode_data_url(url: &Url) -> Result<(String, Vec<u8>)> {
+    // Url keeps everything after `data:` as an opaque, still-encoded path
+    let invalid = |reason: &str| DownloadError::InvalidUrl {
+        url: url.clone(),
+        reason: reason.to_string(),
+    };
+    let (header, data) = url
+        .path()
+        .split_once(',')
+        .ok_or_else(|| invalid("no ',' before the data"))?;
+    let header = percent_decode_str(header).decode_utf8_lossy();
+    let (mime, base64) = match header.strip_suffix(";base64") {
+        Some(mime) => (mime, true),
+        None => (header.as_ref(), false),
+    };
+    let mime = match mime.split(';').next().unwrap_or("") {
+        "" => "text/plain".to_string(),
+        m => m.to_string(),
+    };
+
+    let data: Vec<u8> = percent_decode_str(data).collect();
+    let payload = if base64 {
+        // Whitespace is common in pasted payloads and means nothing here
+        let data: Vec<u8> = data
+            .into_iter()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| invalid(&format!("invalid base64: {e}")))?
+    } else {
+        data
+    };
+    Ok((mime, payload))
+}
+
+/// The whole of what `url` would save, read into memory.
+pub(crate) async fn read(url: &Url, opts: Options) -> Result<Vec<u8>> {
+    check_size(size(url).await?, opts)?;
+    if url.scheme() == "data" {
+        return Ok(decode_data_url(url)?.1);
+    }
+    let src = url.to_file_path().map_err(|_| DownloadError::InvalidUrl {
+        url: url.clone(),
+        reason: "not a local file path".to_string(),
+    })?;
+    fs::read(&src)
+        .await
+        .map_err(DownloadError::io(format!("read {}", src.display())))
+}
+
+/// Size of what `url` would save, for a dry run; fails like a real fetch
+/// would when the file is missing or the `data:` URL is malformed.
+pub(crate) async fn size(url: &Url) -> Result<u64> {
+    if url.scheme() == "data" {
+        return Ok(decode_data_url(url)?.1.len() as u64);
+    }
+    let src = url.to_file_path().map_err(|_| DownloadError::InvalidUrl {
+        url: url.clone(),
+        reason: "not a local file path".to_string(),
+    })?;
+    let meta = fs::metadata(&src)
+        .await
+        .map_err(DownloadError::io(format!("stat {}", src.display())))?;
+    Ok(meta.len())
+}
+
+/// Whether `url` is handled here rather than over HTTP.
+pub(crate) fn is_local(url: &Url) -> bool {
+    matches!(url.scheme(), "file" | "data")
+}
+
+/// Hand `url` to whichever of [`copy_file`] and [`save_data`] applies.
+pub(crate) async fn fetch(
+    shared: &Shared,
+    url: &Url,
+    path: &Path,
+    opts: Options,
+    expected_sha256: Option<&str>,
+) -> Result<Saved> {
+    if url.scheme() == "file" {
+        copy_file(shared, url, path, opts, expected_sha256).await
+    } else {
+        save_data(shared, url, path, opts, expected_sha256).await
+    }
+}
@@ -0,0 +1,242 @@
+//! A live view of every transfer in a batch, for dashboards that draw their
+//! own progress instead of the stacked bars.
+
+use indicatif::ProgressBar;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use crate::{DownloadError, DownloadResult, Status};
+
+/// Where a transfer is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    /// Waiting for a download slot.
+    Queued,
+    /// Holding a slot and fetching.
+    Active,
+    /// Saved, or already up to date.
+    Done,
+    /// Every attempt failed.
+    Failed,
+    /// Cancelled before or during the transfer, or left out by its size.
+    Cancelled,
+}
+
+/// One URL's progress at the moment of a [`Monitor::transfers`] call.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub url: Url,
+    pub path: PathBuf,
+    pub state: TransferState,
+    /// Bytes in the file so far, resumed ones included.
+    pub received: u64,
+    /// Full size, when the server said.
+    pub total: Option<u64>,
+    /// Recent throughput.
+    pub bytes_per_sec: f64,
+    pub eta: Option<Duration>,
+    /// Failed attempts that were retried.
+    pub retries: u32,
+    /// The latest attempt's error, or the final one.
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    url: Url,
+    path: PathBuf,
+    state: TransferState,
+    /// Bar of the current attempt; it keeps counting even when hidden.
+    bar: Option<ProgressBar>,
+    retries: u32,
+    error: Option<String>,
+    cancel: Option<CancellationToken>,
+}
+
+/// Shared with a [`Downloader`](crate::Downloader) through
+/// [`DownloaderBuilder::monitor`](crate::DownloaderBuilder::monitor), then
+/// polled from elsewhere while the batch runs.
+#[derive(Debug, Default)]
+pub struct Monitor {
+    slots: Mutex<Vec<Slot>>,
+    /// Retries and failures, oldest first.
+    log: Mutex<Vec<String>>,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Monitor::default()
+    }
+
+    /// Every URL handed to the downloader so far, in batch order.
+    pub fn transfers(&self) -> Vec<Transfer> {
+        let slots = self.slots.lock().unwrap();
+        slots
+            .iter()
+            .map(|s| {
+                let bar = s.bar.as_ref();
+                let total = bar.and_then(ProgressBar::length);
+                Transfer {
+                    url: s.url.clone(),
+                    path: s.path.clone(),
+                    state: s.state,
+                    received: bar.map_or(0, ProgressBar::position),
+                    total,
+                    bytes_per_sec: bar.map_or(0.0, ProgressBar::per_sec),
+                    eta: bar.filter(|_| total.is_some()).map(ProgressBar::eta),
+                    retries: s.retries,
+                    error: s.error.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Retry and failure messages so far, oldest first.
+    pub fn log(&self) -> Vec<String> {
+        self.log.lock().unwrap().clone()
+    }
+
+    /// Stop transfer `index` (as numbered by [`transfers`](Self::transfers));
+    /// it ends up interrupted, or skipped if it hadn't started.
+    pub fn cancel(&self, index: usize) {
+        if let Some(token) = self
+            .slots
+            .lock()
+            .unwrap()
+            .get(index)
+            .and_then(|s| s.cancel.as_ref())
+        {
+            token.cancel();
+        }
+    }
+
+    /// Register `url`, cancelled through `cancel`, as queued.
+    pub(crate) fn add(
+        self: &Arc<Self>,
+        url: Url,
+        path: PathBuf,
+        cancel: CancellationToken,
+    ) -> Tracker {
+        let mut slots = self.slots.lock().unwrap();
+        slots.push(Slot {
+            url,
+            path,
+            state: TransferState::Queued,
+            bar: None,
+            retries: 0,
+            error: None,
+            cancel: Some(cancel),
+        });
+        Tracker {
+            monitor: self.clone(),
+            index: slots.len() - 1,
+        }
+    }
+}
+
+/// One task's handle on its [`Monitor`] slot.
+#[derive(Debug, Clone)]
+pub(crate) struct Tracker {
+    monitor: Arc<Monitor>,
+    index: usize,
+}
+
+impl Tracker {
+    fn update<T>(&self, f: impl FnOnce(&mut Slot) -> T) -> T {
+        f(&mut self.monitor.slots.lock().unwrap()[self.index])
+    }
+
+    pub(crate) fn start(&self) {
+        self.update(|s| s.state = TransferState::Active);
+    }
+
+    /// Follow `bar` from now on; each attempt brings a new one.
+    pub(crate) fn attach(&self, bar: &ProgressBar) {
+        self.update(|s| s.bar = Some(bar.clone()));
+    }
+
+    /// Add a line to the monitor's log.
+    fn note(&self, line: String) {
+        self.monitor.log.lock().unwrap().push(line);
+    }
+
+    /// Attempt `attempt` of `max` at `url` (the URL or a mirror) failed with
+    /// `error` and will be retried.
+    pub(crate) fn retry(&self, url: &Url, attempt: u32, max: u32, error: &DownloadError) {
+        self.update(|s| {
+            s.retries += 1;
+            s.error = Some(error.to_string());
+        });
+        self.note(format!("attempt {attempt}/{max} for {url} failed: {error}"));
+    }
+
+    pub(crate) fn finish(&self, res: &DownloadResult) {
+        let state = match res.status {
+            Status::Saved | Status::UpToDate | Status::NotModified | Status::Planned => {
+                TransferState::Done
+            }
+            Status::Failed => TransferState::Failed,
+            Status::Interrupted | Status::Skipped | Status::Filtered => TransferState::Cancelled,
+        };
+        if let (TransferState::Failed, Some(e)) = (state, &res.error) {
+            self.note(format!("FAILED {}: {e}", res.url));
+        }
+        self.update(|s| {
+            s.state = state;
+            s.path = res.path.clone();
+            s.error = res.error.as_ref().map(ToString::to_string);
+            s.cancel = None;
+        });
+    }
+}
+
+/// Running totals for a batch, cheap enough to update on every chunk and
+/// readable from anywhere, such as a signal handler printing a status line.
+///
+/// Hand one to [`DownloaderBuilder::stats`](crate::DownloaderBuilder::stats);
+/// each [`download`](crate::Downloader::download) call adds to it.
+#[derive(Debug, Default)]
+pub struct BatchStats {
+    files: AtomicU64,
+    finished: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl BatchStats {
+    pub fn new() -> Self {
+        BatchStats::default()
+    }
+
+    /// Files handed to the downloader so far.
+    pub fn files(&self) -> u64 {
+        self.files.load(Ordering::Relaxed)
+    }
+
+    /// Files whose download is over, however it ended.
+    pub fn finished(&self) -> u64 {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received over the network (or copied, for `file://` URLs) so
+    /// far; bytes a resumed file already had don't count.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_files(&self, n: u64) {
+        self.files.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn file_done(&self) {
+        self.finished.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add `n` bytes, returning the new total.
+    pub(crate) fn received(&self, n: u64) -> u64 {
+        self.bytes.fetch_add(n, Ordering::Relaxed) + n
+    }
+}
@@ -0,0 +1,559 @@
+//! Choosing where a download lands on disk.
+
+use anyhow::{bail, Result};
+use percent_encoding::percent_decode_str;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing::warn;
+use url::Url;
+
+/// What to do when a download's file already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Save to the first free `name (N).ext` instead.
+    #[default]
+    Rename,
+    /// Replace the existing file.
+    Overwrite,
+    /// Leave the existing file alone and don't download.
+    Skip,
+    /// Skip it if it already has the server's size, resume it if shorter,
+    /// replace it if longer.
+    Resume,
+    /// Fail the download.
+    Error,
+}
+
+impl OnConflict {
+    /// Lowercase name, as accepted by `from_str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OnConflict::Rename => "rename",
+            OnConflict::Overwrite => "overwrite",
+            OnConflict::Skip => "skip",
+            OnConflict::Resume => "resume",
+            OnConflict::Error => "error",
+        }
+    }
+
+    /// Whether a download keeps its planned name even when a file has it.
+    pub(crate) fn keeps_name(self) -> bool {
+        self != OnConflict::Rename
+    }
+}
+
+impl FromStr for OnConflict {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "rename" => OnConflict::Rename,
+            "overwrite" => OnConflict::Overwrite,
+            "skip" => OnConflict::Skip,
+            "resume" => OnConflict::Resume,
+            "error" => OnConflict::Error,
+            _ => bail!(
+                "unknown conflict policy '{s}', expected overwrite, skip, rename, resume or error"
+            ),
+        })
+    }
+}
+
+/// How downloads of one batch that want the same file name are told apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NumberBy {
+    /// Later URLs in the input get `name (1).ext`, `name (2).ext`, ... in
+    /// order. Names are handed out before any download starts, so reruns
+    /// map URLs to the same files; only a server-suggested name, known once
+    /// its response arrives, is numbered in arrival order.
+    #[default]
+    UrlOrder,
+    /// Never number: a URL whose name an earlier one in the batch already
+    /// has fails, and a server-suggested name that's taken is ignored.
+    None,
+}
+
+impl NumberBy {
+    /// Name as accepted by `from_str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NumberBy::UrlOrder => "url-order",
+            NumberBy::None => "none",
+        }
+    }
+}
+
+impl FromStr for NumberBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "url-order" => NumberBy::UrlOrder,
+            "none" => NumberBy::None,
+            _ => bail!("unknown numbering '{s}', expected url-order or none"),
+        })
+    }
+}
+
+/// Longest file name we produce, in bytes. Most filesystems allow 255; the
+/// rest leaves room for a ` (N)` suffix and the `.part` extension.
+const MAX_NAME_LEN: usize = 200;
+
+/// Last path segment of `url`, percent-decoded and made safe to use as a file
+/// name. A URL ending in `/` gives `index.html`; one with no path at all (such
+/// as `data:`) gives `download`.
+pub fn file_name_from_url(url: &Url) -> String {
+    name_from_url(url, false)
+}
+
+/// [`file_name_from_url`] for a file about to be saved under that name,
+/// warning if it had to be renamed.
+pub(crate) fn saved_name_from_url(url: &Url) -> String {
+    name_from_url(url, true)
+}
+
+fn name_from_url(url: &Url, loud: bool) -> String {
+    let Some(last) = url.path_segments().and_then(|mut segs| segs.next_back()) else {
+        return "download".to_string();
+    };
+    if last.is_empty() {
+        return "index.html".to_string();
+    }
+    // An encoded slash is part of the name, not a directory
+    let decoded = percent_decode_str(last)
+        .decode_utf8_lossy()
+        .replace(['/', '\\'], "_");
+    sanitize(&decoded, loud).unwrap_or_else(|| "download".to_string())
+}
+
+/// Directory part of `url`'s path, minus its first `cut_dirs` components, as
+/// a relative path to recreate under the output directory.
+///
+/// Segments that could climb out of it (`..`, anything with a separator) or
+/// are otherwise unusable as a directory name are dropped.
+pub(crate) fn url_dirs(url: &Url, cut_dirs: usize) -> PathBuf {
+    let Some(segs) = url.path_segments() else {
+        return PathBuf::new();
+    };
+    let segs: Vec<&str> = segs.collect();
+    let dirs = &segs[..segs.len().saturating_sub(1)];
+    dirs.iter()
+        .filter(|s| !s.is_empty())
+        .skip(cut_dirs)
+        .filter(|s| {
+            **s != "." && **s != ".." && !s.contains(['/', '\\']) && !s.contains(char::is_control)
+        })
+        .collect()
+}
+
+/// `out_dir/base`, or the first free `stem (N).ext` variant unless overwriting.
+///
+/// Paths in `taken` already belong to another download and count as used
+/// even when overwriting.
+fn pick_output_path(
+    out_dir: &Path,
+    base: &str,
+    overwrite: bool,
+    taken: &HashSet<PathBuf>,
+) -> PathBuf {
+    let path = out_dir.join(base);
+    if !taken.contains(&path) && (overwrite || !path.exists()) {
+        return path;
+    }
+
+    let stem = Path::new(base)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("file");
+    let ext = Path::new(base)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
+
+    for i in 1..=9999 {
+        let candidate = if ext.is_empty() {
+            out_dir.join(format!("{stem} ({i})"))
+        } else {
+            out_dir.join(format!("{stem} ({i}).{ext}"))
+        };
+        if !taken.contains(&candidate) && !candidate.exists() {
+            return candidate;
+        }
+    }
+    path
+}
+
+/// [`pick_output_path`] and claim the result in `reserved` under one lock,
+/// so concurrent tasks can never settle on the same name.
+pub(crate) fn reserve_output_path(
+    reserved: &Mutex<HashSet<PathBuf>>,
+    out_dir: &Path,
+    base: &str,
+    overwrite: bool,
+) -> PathBuf {
+    let mut reserved = reserved.lock().unwrap();
+    let path = pick_output_path(out_dir, base, overwrite, &reserved);
+    reserved.insert(path.clone());
+    path
+}
+
+/// [`reserve_output_path`] without numbering: `None` if another download of
+/// the batch already has `out_dir/base`. Conflicts with files on disk are
+/// still settled by `overwrite`.
+pub(crate) fn reserve_unnumbered(
+    reserved: &Mutex<HashSet<PathBuf>>,
+    out_dir: &Path,
+    base: &str,
+    overwrite: bool,
+) -> Option<PathBuf> {
+    let mut reserved = reserved.lock().unwrap();
+    if reserved.contains(&out_dir.join(base)) {
+        return None;
+    }
+    let path = pick_output_path(out_dir, base, overwrite, &reserved);
+    reserved.insert(path.clone());
+    Some(path)
+}
+
+/// Switch a download planned at `planned` to `base`, a name that only
+/// turned up mid-transfer (from the server, or an added extension), in the
+/// same directory. Unnumbered, a name that's taken leaves it at `planned`.
+pub(crate) fn reserve_renamed(
+    reserved: &Mutex<HashSet<PathBuf>>,
+    planned: &Path,
+    base: &str,
+    overwrite: bool,
+    number_by: NumberBy,
+) -> PathBuf {
+    let dir = planned.parent().unwrap_or(Path::new("."));
+    match number_by {
+        NumberBy::UrlOrder => reserve_output_path(reserved, dir, base, overwrite),
+        NumberBy::None => reserve_unnumbered(reserved, dir, base, overwrite)
+            .unwrap_or_else(|| planned.to_path_buf()),
+    }
+}
+
+/// Give back a path claimed with [`reserve_output_path`] that won't be used
+/// after all.
+pub(crate) fn release_output_path(reserved: &Mutex<HashSet<PathBuf>>, path: &Path) {
+    reserved.lock().unwrap().remove(path);
+}
+
+/// Usual file extension for a `Content-Type` header value, parameters ignored.
+pub(crate) fn extension_for_mime(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    let ext = match mime.as_str() {
+        "text/plain" => "txt",
+        "text/html" => "html",
+        "text/css" => "css",
+        "text/csv" => "csv",
+        "text/xml" | "application/xml" => "xml",
+        "text/javascript" | "application/javascript" => "js",
+        "application/json" => "json",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-7z-compressed" => "7z",
+        "application/wasm" => "wasm",
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "image/x-icon" | "image/vnd.microsoft.icon" => "ico",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        _ => return None,
+    };
+    Some(ext)
+}
+
+/// Extract the filename from a `Content-Disposition` header value.
+///
+/// `filename*=` (RFC 5987, percent-encoded with a charset) takes precedence over
+/// a plain `filename=`. The result is reduced to a bare file name so a hostile
+/// header can't point outside the output directory.
+pub(crate) fn content_disposition_filename(header: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for param in split_header_params(header).into_iter().skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => extended = decode_rfc5987(value.trim()),
+            "filename" => plain = Some(unquote(value.trim())),
+            _ => {}
+        }
+    }
+    extended.or(plain).and_then(|n| sanitize_file_name(&n))
+}
+
+/// Split a header value on `;`, ignoring separators inside quoted strings.
+fn split_header_params(header: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut cur = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in header.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(std::mem::take(&mut cur));
+                continue;
+            }
+            _ => {}
+        }
+        cur.push(c);
+    }
+    params.push(cur);
+    params
+}
+
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                out.push(next);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Decode an RFC 5987 `charset'lang'value` extended parameter.
+fn decode_rfc5987(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?.to_ascii_lowercase();
+    let _lang = parts.next()?;
+    let bytes: Vec<u8> = percent_decode_str(parts.next()?).collect();
+    match charset.as_str() {
+        "utf-8" => String::from_utf8(bytes).ok(),
+        "iso-8859-1" => Some(bytes.into_iter().map(char::from).collect()),
+        _ => None,
+    }
+}
+
+/// Reduce a server-supplied name to a single safe path component.
+///
+/// Characters Windows refuses (`<>:"|?*`) become `_`, trailing dots and
+/// spaces go, device names like `CON` or `lpt1.txt` get a `_` prefix, and
+/// long names are cut to [`MAX_NAME_LEN`] bytes, keeping the extension. The
+/// last two are done on every platform so a batch names its files the same
+/// way everywhere, with a warning since the result can be surprising.
+pub(crate) fn sanitize_file_name(name: &str) -> Option<String> {
+    sanitize(name, true)
+}
+
+fn sanitize(name: &str, loud: bool) -> Option<String> {
+    let base = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or("")
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let base = base
+        .trim_start()
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace());
+    if base.is_empty() {
+        return None;
+    }
+    let safe = if is_reserved_name(base) {
+        format!("_{base}")
+    } else {
+        base.to_string()
+    };
+    let safe = truncate_name(&safe);
+    if loud && safe != base {
+        warn!(name = base, saved_as = %safe, "file name is reserved or too long; renamed");
+    }
+    Some(safe)
+}
+
+/// Whether Windows reserves `name` for a device. Only the part before the
+/// first dot counts, in any case: `aux.tar.gz` is as unusable as `AUX`.
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    matches!(
+        stem.to_ascii_uppercase().as_bytes(),
+        b"CON"
+            | b"PRN"
+            | b"AUX"
+            | b"NUL"
+            | [b'C', b'O', b'M', b'1'..=b'9']
+            | [b'L', b'P', b'T', b'1'..=b'9']
+    )
+}
+
+/// Cut `name` to at most [`MAX_NAME_LEN`] bytes on a character boundary,
+/// shortening the stem so a reasonable extension survives.
+fn truncate_name(name: &str) -> String {
+    if name.len() <= MAX_NAME_LEN {
+        return name.to_string();
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() <= 16 => (stem, ext),
+        _ => (name, ""),
+    };
+    let mut keep = MAX_NAME_LEN - if ext.is_empty() { 0 } else { ext.len() + 1 };
+    while !stem.is_char_boundary(keep) {
+        keep -= 1;
+    }
+    if ext.is_empty() {
+        stem[..keep].to_string()
+    } else {
+        format!("{}.{ext}", &stem[..keep])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn name_of(url: &str) -> String {
+        file_name_from_url(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn file_name_is_decoded() {
+        assert_eq!(name_of("http://h/dir/my%20file.txt"), "my file.txt");
+        assert_eq!(name_of("http://h/a%2Fb.txt"), "a_b.txt");
+    }
+
+    #[test]
+    fn file_name_ignores_query_and_fragment() {
+        assert_eq!(name_of("http://h/file.zip?token=abc&x=1"), "file.zip");
+        assert_eq!(name_of("http://h/file.zip#part"), "file.zip");
+    }
+
+    #[test]
+    fn file_name_falls_back_without_a_last_segment() {
+        assert_eq!(name_of("http://h/dir/"), "index.html");
+        assert_eq!(name_of("http://h"), "index.html");
+        assert_eq!(name_of("data:text/plain,hi"), "download");
+    }
+
+    #[test]
+    fn device_names_are_reserved_in_any_case() {
+        for name in [
+            "CON",
+            "prn",
+            "Aux",
+            "nul",
+            "COM1",
+            "lpt9",
+            "aux.tar.gz",
+            "con .txt",
+        ] {
+            assert!(is_reserved_name(name), "{name}");
+        }
+        for name in ["CONSOLE", "com0", "LPT", "auxiliary.txt", "my_con"] {
+            assert!(!is_reserved_name(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn sanitize_renames_reserved_names() {
+        assert_eq!(sanitize("CON", false).as_deref(), Some("_CON"));
+        assert_eq!(sanitize("prn.txt", false).as_deref(), Some("_prn.txt"));
+        assert_eq!(
+            sanitize("aux.tar.gz", false).as_deref(),
+            Some("_aux.tar.gz")
+        );
+    }
+
+    #[test]
+    fn sanitize_strips_trailing_dots_and_spaces() {
+        assert_eq!(sanitize("report. . ", false).as_deref(), Some("report"));
+        assert_eq!(
+            sanitize("  notes.txt...", false).as_deref(),
+            Some("notes.txt")
+        );
+        // What's left of a reserved name is still reserved
+        assert_eq!(sanitize("NUL.", false).as_deref(), Some("_NUL"));
+        assert_eq!(sanitize(" . ..", false), None);
+    }
+
+    #[test]
+    fn sanitize_replaces_forbidden_characters() {
+        assert_eq!(
+            sanitize("a<b>c:d|e?f*.txt", false).as_deref(),
+            Some("a_b_c_d_e_f_.txt")
+        );
+        assert_eq!(sanitize("dir/sub\\file", false).as_deref(), Some("file"));
+    }
+
+    #[test]
+    fn truncate_name_keeps_the_extension() {
+        let long = format!("{}.tar", "a".repeat(300));
+        let cut = truncate_name(&long);
+        assert_eq!(cut.len(), MAX_NAME_LEN);
+        assert!(cut.ends_with("aaa.tar"));
+
+        // Never splits a character
+        let wide = format!("{}.txt", "é".repeat(150));
+        let cut = truncate_name(&wide);
+        assert!(cut.len() <= MAX_NAME_LEN);
+        assert!(cut.ends_with("é.txt"));
+
+        // Something too long to be an extension is cut like the rest
+        let odd = format!("x.{}", "b".repeat(300));
+        assert_eq!(truncate_name(&odd).len(), MAX_NAME_LEN);
+        assert_eq!(truncate_name("short.txt"), "short.txt");
+    }
+
+    #[test]
+    fn sanitized_names_can_be_created_here() {
+        // Whatever the platform, every one of these has to become a file
+        let dir = std::env::temp_dir().join(format!("mt-downloader-names-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let long = format!("{}.bin", "z".repeat(400));
+        for name in ["CON", "aux.txt", "trailing. ", "a<b>:c", long.as_str()] {
+            let safe = sanitize(name, false).unwrap();
+            std::fs::write(dir.join(&safe), b"x").unwrap_or_else(|e| panic!("{safe}: {e}"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_reservations_get_distinct_paths() {
+        let reserved = Arc::new(Mutex::new(HashSet::new()));
+        let out_dir = std::env::temp_dir().join("mt-downloader-reserve-test");
+        let tasks: Vec<_> = (0..64)
+            .map(|_| {
+                let reserved = reserved.clone();
+                let out_dir = out_dir.clone();
+                tokio::spawn(
+                    async move { reserve_output_path(&reserved, &out_dir, "file.bin", false) },
+                )
+            })
+            .collect();
+        let mut paths = HashSet::new();
+        for task in tasks {
+            assert!(paths.insert(task.await.unwrap()));
+        }
+        assert_eq!(paths.len(), 64);
+        assert!(paths.contains(&out_dir.join("file.bin")));
+        assert!(paths.contains(&out_dir.join("file (63).bin")));
+    }
+}
@@ -0,0 +1,157 @@
+//! Per-host credentials from a `.netrc` file, as curl and ftp read them.
+
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::path::Path;
+
+/// One `machine` (or `default`) entry.
+#[derive(Clone)]
+struct Entry {
+    /// `None` for the `default` entry, which matches any host.
+    machine: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+/// The entries of a `.netrc` file, in file order.
+#[derive(Clone, Default)]
+pub struct Netrc {
+    entries: Vec<Entry>,
+}
+
+impl fmt::Debug for Netrc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let machines = self
+            .entries
+            .iter()
+            .map(|e| e.machine.as_deref().unwrap_or("default"));
+        f.debug_struct("Netrc")
+            .field("machines", &machines.collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Netrc {
+    /// Read and parse the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read netrc file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("netrc file {}", path.display()))
+    }
+
+    /// Parse netrc text: whitespace-separated `machine`, `default`, `login`,
+    /// `password` and `account` tokens, values optionally in double quotes.
+    /// `macdef` definitions are skipped.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut tokens = Tokens::new(text);
+        while let Some(token) = tokens.next() {
+            let mut value = |what: &str| {
+                tokens
+                    .next()
+                    .with_context(|| format!("'{what}' without a value"))
+            };
+            match token.as_str() {
+                "machine" => entries.push(Entry {
+                    machine: Some(value("machine")?.to_ascii_lowercase()),
+                    login: None,
+                    password: None,
+                }),
+                "default" => entries.push(Entry {
+                    machine: None,
+                    login: None,
+                    password: None,
+                }),
+                "login" | "password" | "account" => {
+                    let v = value(&token)?;
+                    let Some(entry) = entries.last_mut() else {
+                        bail!("'{token}' before any 'machine' or 'default'");
+                    };
+                    match token.as_str() {
+                        "login" => entry.login = Some(v),
+                        "password" => entry.password = Some(v),
+                        _ => {}
+                    }
+                }
+                "macdef" => {
+                    value("macdef")?;
+                    tokens.skip_macro();
+                }
+                other => bail!("unexpected token '{other}'"),
+            }
+        }
+        Ok(Netrc { entries })
+    }
+
+    /// Login and password for `host`: its own `machine` entry if there is
+    /// one, else the `default` entry. Entries without a login don't count.
+    pub(crate) fn credentials(&self, host: &str) -> Option<(&str, Option<&str>)> {
+        let host = host.to_ascii_lowercase();
+        self.entries
+            .iter()
+            .find(|e| e.machine.as_deref() == Some(host.as_str()))
+            .or_else(|| self.entries.iter().find(|e| e.machine.is_none()))
+            .and_then(|e| Some((e.login.as_deref()?, e.password.as_deref())))
+    }
+}
+
+/// Splits netrc text into tokens.
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(text: &'a str) -> Self {
+        Tokens { rest: text }
+    }
+
+    /// A macro body runs to the first empty line.
+    fn skip_macro(&mut self) {
+        self.rest = match self.rest.find("\n\n") {
+            Some(end) => &self.rest[end + 2..],
+            None => "",
+        };
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            self.rest = self.rest.trim_start();
+            // `#` comments run to the end of the line
+            match self.rest.strip_prefix('#') {
+                Some(comment) => self.rest = comment.split_once('\n').map_or("", |(_, r)| r),
+                None => break,
+            }
+        }
+        if self.rest.is_empty() {
+            return None;
+        }
+        if let Some(quoted) = self.rest.strip_prefix('"') {
+            let mut token = String::new();
+            let mut chars = quoted.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        self.rest = &quoted[i + 1..];
+                        return Some(token);
+                    }
+                    '\\' => token.extend(chars.next().map(|(_, c)| c)),
+                    c => token.push(c),
+                }
+            }
+            self.rest = "";
+            return Some(token);
+        }
+        let end = self
+            .rest
+            .find(char::is_whitespace)
+            .unwrap_or(self.rest.len());
+        let (token, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Some(token.to_string())
+    }
+}
@@ -0,0 +1,225 @@
+//! Certificate pinning: trusting a TLS server only if its certificate or
+//! public key hashes to one of a few known values.
+//!
+//! The check runs inside the TLS handshake, so a server that fails it never
+//! sees a request, its credentials or its body.
+
+use anyhow::{anyhow, Context};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, OtherError, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// TLS settings for a client that accepts a server only if the SHA-256 of
+/// its public key (its DER `SubjectPublicKeyInfo`, as in `curl
+/// --pinnedpubkey`) or of its whole certificate is among `pins`. Hand them
+/// to [`reqwest::ClientBuilder::use_preconfigured_tls`], along with
+/// `https_only(true)` so that plain HTTP URLs fail too.
+///
+/// With `verify_chain` the certificate must also pass the usual checks
+/// against the system's CA certificates plus `extra_roots`; without it the
+/// pin is all that's trusted. `identity` is a client certificate chain and
+/// its key for mutual TLS. ALPN offers HTTP/2 and HTTP/1.1; set
+/// `alpn_protocols` on the result to narrow that.
+pub fn pinned_tls_config(
+    pins: &[[u8; 32]],
+    extra_roots: Vec<CertificateDer<'static>>,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    verify_chain: bool,
+) -> anyhow::Result<rustls::ClientConfig> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let chain = if verify_chain {
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(system_roots());
+        roots.add_parsable_certificates(extra_roots);
+        if roots.is_empty() {
+            return Err(anyhow!(
+                "no CA certificates found to check pinned servers against; \
+                 set SSL_CERT_FILE or pass them with --cacert"
+            ));
+        }
+        let verifier = WebPkiServerVerifier::builder_with_provider(roots.into(), provider.clone())
+            .build()
+            .context("set up certificate verification")?;
+        Some(verifier)
+    } else {
+        None
+    };
+    let verifier = PinVerifier {
+        pins: pins.to_vec(),
+        chain,
+        provider: provider.clone(),
+    };
+    let builder = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("set up TLS")?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let mut config = match identity {
+        Some((certs, key)) => builder
+            .with_client_auth_cert(certs, key)
+            .context("invalid client certificate or key")?,
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// The reason a request failed, if it's that the server didn't match the
+/// pins.
+pub(crate) fn mismatch(err: &reqwest::Error) -> Option<String> {
+    // Plain HTTP never gets as far as a handshake
+    if err.is_builder() && err.url().is_some_and(|u| u.scheme() != "https") {
+        return Some("not a TLS connection".to_string());
+    }
+    let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(e) = cause {
+        if let Some(rustls::Error::InvalidCertificate(CertificateError::Other(other))) =
+            e.downcast_ref()
+        {
+            if let Some(unpinned) = other.0.downcast_ref::<Unpinned>() {
+                return Some(unpinned.to_string());
+            }
+        }
+        // The TLS layer reports through io::Errors, whose `source()` skips
+        // the error they wrap
+        cause = match e
+            .downcast_ref::<std::io::Error>()
+            .and_then(|io| io.get_ref())
+        {
+            Some(inner) => Some(inner),
+            None => e.source(),
+        };
+    }
+    None
+}
+
+/// What [`PinVerifier`] fails a handshake with.
+#[derive(Debug, thiserror::Error)]
+#[error("its certificate matches none of the pinned hashes")]
+struct Unpinned;
+
+/// Checks the usual things about a server's certificate, unless told not
+/// to, and then that it's pinned.
+#[derive(Debug)]
+struct PinVerifier {
+    pins: Vec<[u8; 32]>,
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(chain) = &self.chain {
+            chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        let mut hashes = vec![Sha256::digest(end_entity)];
+        if let Some(key) = public_key(end_entity) {
+            hashes.push(Sha256::digest(key));
+        }
+        if hashes
+            .iter()
+            .any(|h| self.pins.iter().any(|pin| pin[..] == h[..]))
+        {
+            return Ok(ServerCertVerified::assertion());
+        }
+        Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+            OtherError(Arc::new(Unpinned)),
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// The CA certificates OpenSSL would use: the bundle in `SSL_CERT_FILE`, or
+/// the usual one for this system. Empty where there's no such file.
+fn system_roots() -> Vec<CertificateDer<'static>> {
+    let Some(file) = openssl_probe::probe().cert_file else {
+        return Vec::new();
+    };
+    match CertificateDer::pem_file_iter(&file) {
+        Ok(certs) => certs.filter_map(Result::ok).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The `SubjectPublicKeyInfo` of a DER certificate, header included.
+fn public_key(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = element(cert)?;
+    let (_, mut tbs, _) = element(cert)?;
+    // The explicitly tagged version is optional
+    if tbs.first() == Some(&0xa0) {
+        tbs = element(tbs)?.2;
+    }
+    // Serial number, signature algorithm, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = element(tbs)?.2;
+    }
+    let (_, _, rest) = element(tbs)?;
+    Some(&tbs[..tbs.len() - rest.len()])
+}
+
+/// Split the DER element at the start of `der` into its tag, its contents
+/// and whatever follows it.
+fn element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, der) = der.split_first()?;
+    let (&first, mut der) = der.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || der.len() < n {
+            return None;
+        }
+        let len = der[..n].iter().fold(0, |len, b| len << 8 | *b as usize);
+        der = &der[n..];
+        len
+    };
+    if der.len() < len {
+        return None;
+    }
+    Some((tag, &der[..len], &der[len..]))
+}
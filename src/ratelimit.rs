@@ -0,0 +1,55 @@
+//! Token-bucket bandwidth limiting shared across concurrent transfers.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Caps the combined throughput of every transfer that shares it.
+///
+/// The bucket holds at most one second's worth of tokens. A caller may take
+/// more than is available, even more than the whole bucket when a single chunk
+/// is huge; the balance goes negative and the caller sleeps until that debt
+/// has been paid back, so large chunks are delayed rather than deadlocked.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        RateLimiter {
+            bytes_per_sec: rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `bytes` may be written without exceeding the rate.
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut b = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(b.last).as_secs_f64() * self.bytes_per_sec;
+            b.tokens = (b.tokens + refill).min(self.bytes_per_sec);
+            b.last = now;
+            b.tokens -= bytes as f64;
+            if b.tokens < 0.0 {
+                Duration::from_secs_f64(-b.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Fetching private S3 objects: `s3://bucket/key` URLs and AWS Signature
+//! Version 4 on requests to S3 hosts.
+
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use percent_encoding::{
+    percent_decode_str, percent_encode, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC,
+};
+use reqwest::{Method, RequestBuilder};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt;
+use std::time::SystemTime;
+use url::Url;
+
+use crate::download::to_hex;
+
+/// Characters SigV4 leaves alone when encoding a path or query; everything
+/// else is percent-encoded.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Region assumed when neither the host nor the credentials name one.
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// AWS credentials to sign S3 requests with.
+#[derive(Clone)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// For temporary credentials, e.g. from an assumed role.
+    pub session_token: Option<String>,
+    /// Region for `s3://` URLs and for hosts that don't name one.
+    pub region: Option<String>,
+}
+
+impl fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("region", &self.region)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AwsCredentials {
+    /// Credentials the way the AWS CLI finds them: `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY` (plus `AWS_SESSION_TOKEN`), else the profile
+    /// named by `AWS_PROFILE`, or `default`, if the credentials file has it.
+    /// The region comes from `AWS_REGION` or `AWS_DEFAULT_REGION`, else the
+    /// profile's config.
+    pub fn from_env() -> Option<Self> {
+        let env = |key| std::env::var(key).ok().filter(|v: &String| !v.is_empty());
+        let region = env("AWS_REGION").or_else(|| env("AWS_DEFAULT_REGION"));
+        if let (Some(access_key_id), Some(secret_access_key)) =
+            (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY"))
+        {
+            return Some(AwsCredentials {
+                access_key_id,
+                secret_access_key,
+                session_token: env("AWS_SESSION_TOKEN"),
+                region,
+            });
+        }
+        let profile = env("AWS_PROFILE").unwrap_or_else(|| "default".to_string());
+        let mut creds = Self::from_profile(&profile).ok()?;
+        creds.region = region.or(creds.region);
+        Some(creds)
+    }
+
+    /// Credentials of `profile` in `~/.aws/credentials` (or
+    /// `AWS_SHARED_CREDENTIALS_FILE`), with its region from `~/.aws/config`
+    /// (or `AWS_CONFIG_FILE`) if set there.
+    pub fn from_profile(profile: &str) -> Result<Self> {
+        let path = aws_file("AWS_SHARED_CREDENTIALS_FILE", "~/.aws/credentials");
+        let text = std::fs::read_to_string(&path).with_context(|| format!("read {path}"))?;
+        let section = ini_section(&text, profile)
+            .ok_or_else(|| anyhow!("no profile '{profile}' in {path}"))?;
+        let get = |key: &str| {
+            section
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+        };
+        let key = |name: &str| {
+            get(name).ok_or_else(|| anyhow!("profile '{profile}' in {path} has no {name}"))
+        };
+        // The config file names every profile but the default `profile <name>`
+        let config = std::fs::read_to_string(aws_file("AWS_CONFIG_FILE", "~/.aws/config"))
+            .unwrap_or_default();
+        let region = ini_section(&config, &format!("profile {profile}"))
+            .or_else(|| ini_section(&config, profile))
+            .and_then(|s| s.into_iter().find(|(k, _)| k == "region"))
+            .map(|(_, v)| v)
+            .or_else(|| get("region"));
+        Ok(AwsCredentials {
+            access_key_id: key("aws_access_key_id")?,
+            secret_access_key: key("aws_secret_access_key")?,
+            session_token: get("aws_session_token"),
+            region,
+        })
+    }
+
+    fn region(&self) -> &str {
+        self.region.as_deref().unwrap_or(DEFAULT_REGION)
+    }
+}
+
+/// Path of an AWS config file: `var` if set, else `default` under home.
+fn aws_file(var: &str, default: &str) -> String {
+    std::env::var(var).unwrap_or_else(|_| shellexpand::tilde(default).into_owned())
+}
+
+/// `key = value` pairs of the `[name]` section of an INI file, keys
+/// lowercased.
+fn ini_section(text: &str, name: &str) -> Option<Vec<(String, String)>> {
+    let mut lines = text.lines().map(str::trim);
+    lines.find(|l| {
+        l.strip_prefix('[')
+            .and_then(|l| l.strip_suffix(']'))
+            .map(str::trim)
+            == Some(name)
+    })?;
+    Some(
+        lines
+            .take_while(|l| !l.starts_with('['))
+            .filter(|l| !l.starts_with(['#', ';']))
+            .filter_map(|l| l.split_once('='))
+            .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+            .collect(),
+    )
+}
+
+/// Whether `url` is on an S3 endpoint, and the region its host names, if any:
+/// `bucket.s3.eu-west-1.amazonaws.com`, `s3.eu-west-1.amazonaws.com/bucket`,
+/// the global `bucket.s3.amazonaws.com` or the older `s3-eu-west-1` form.
+fn s3_host(url: &Url) -> Option<Option<String>> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let labels: Vec<&str> = host.strip_suffix(".amazonaws.com")?.split('.').collect();
+    let i = labels
+        .iter()
+        .position(|l| *l == "s3" || l.starts_with("s3-"))?;
+    let region = match labels[i].strip_prefix("s3-") {
+        Some("external-1") => Some(DEFAULT_REGION),
+        Some(region) => Some(region),
+        None => labels[i + 1..].iter().copied().find(|l| *l != "dualstack"),
+    };
+    Some(region.map(str::to_string))
+}
+
+/// `url` as it should be requested: an `s3://` URL becomes HTTPS, and with
+/// credentials an S3 URL's path is encoded exactly as [`sign`] encodes it.
+pub(crate) fn resolve<'a>(url: &'a Url, creds: Option<&AwsCredentials>) -> Cow<'a, Url> {
+    let mut url = match url.scheme() {
+        "s3" => Cow::Owned(https_url(url, creds)),
+        _ => Cow::Borrowed(url),
+    };
+    if creds.is_some() && s3_host(&url).is_some() && !presigned(&url) {
+        let path = canonical_path(url.path());
+        if path != url.path() {
+            url.to_mut().set_path(&path);
+        }
+    }
+    url
+}
+
+/// The HTTPS URL for an `s3://bucket/key` one: virtual-hosted style, or path
+/// style for bucket names with dots, which the endpoint's certificate
+/// doesn't cover.
+fn https_url(url: &Url, creds: Option<&AwsCredentials>) -> Url {
+    let bucket = url.host_str().unwrap_or_default();
+    let endpoint = match creds.and_then(|c| c.region.as_deref()) {
+        Some(region) => format!("s3.{region}.amazonaws.com"),
+        None => "s3.amazonaws.com".to_string(),
+    };
+    let base = if bucket.contains('.') {
+        format!("https://{endpoint}/{bucket}")
+    } else {
+        format!("https://{bucket}.{endpoint}")
+    };
+    let mut https: Url = base.parse().unwrap_or_else(|_| url.clone());
+    let path = format!("{}{}", https.path().trim_end_matches('/'), url.path());
+    https.set_path(&path);
+    https.set_query(url.query());
+    https
+}
+
+fn presigned(url: &Url) -> bool {
+    url.query_pairs()
+        .any(|(k, _)| k.eq_ignore_ascii_case("X-Amz-Signature"))
+}
+
+/// `path` with every byte but unreserved ones and the `/` separators
+/// percent-encoded, as SigV4 wants it.
+fn canonical_path(path: &str) -> String {
+    path.split('/')
+        .map(|seg| {
+            let raw = percent_decode_str(seg).collect::<Vec<u8>>();
+            percent_encode(&raw, UNRESERVED).to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Add a SigV4 `Authorization` header to `req`, a `method` request for
+/// `url`, when it goes to S3 and isn't presigned already. Only the host and
+/// the `x-amz-*` headers added here are signed, so others (like `Range`)
+/// can be set afterwards; the body isn't signed either.
+pub(crate) fn sign(
+    req: RequestBuilder,
+    method: &Method,
+    url: &Url,
+    creds: &AwsCredentials,
+) -> RequestBuilder {
+    let Some(host_region) = s3_host(url) else {
+        return req;
+    };
+    if presigned(url) {
+        return req;
+    }
+    let region = host_region.as_deref().unwrap_or(creds.region());
+    // 2026-10-15T03:09:07Z -> 20261015T030907Z
+    let stamp: String = humantime::format_rfc3339_seconds(SystemTime::now())
+        .to_string()
+        .chars()
+        .filter(|c| !matches!(c, '-' | ':'))
+        .collect();
+    let date = &stamp[..8];
+    let payload = "UNSIGNED-PAYLOAD";
+
+    let host = match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", payload.to_string()),
+        ("x-amz-date", stamp.clone()),
+    ];
+    if let Some(token) = &creds.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let signed_headers = headers
+        .iter()
+        .map(|(k, _)| *k)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers.iter().map(|(k, v)| format!("{k}:{v}\n")).collect();
+
+    let path = canonical_path(url.path());
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| {
+            (
+                utf8_percent_encode(&k, UNRESERVED).to_string(),
+                utf8_percent_encode(&v, UNRESERVED).to_string(),
+            )
+        })
+        .collect();
+    query.sort();
+    let query = query
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let canonical =
+        format!("{method}\n{path}\n{query}\n{canonical_headers}\n{signed_headers}\n{payload}");
+    let scope = format!("{date}/{region}/s3/aws4_request");
+    let to_sign = format!(
+        "AWS4-HMAC-SHA256\n{stamp}\n{scope}\n{}",
+        to_hex(&Sha256::digest(canonical.as_bytes()))
+    );
+    let mut key = hmac(format!("AWS4{}", creds.secret_access_key).as_bytes(), date);
+    for part in [region, "s3", "aws4_request"] {
+        key = hmac(&key, part);
+    }
+    let signature = to_hex(&hmac(&key, &to_sign));
+
+    let mut req = req
+        .header("x-amz-content-sha256", payload)
+        .header("x-amz-date", &stamp)
+        .header(
+            reqwest::header::AUTHORIZATION,
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                creds.access_key_id
+            ),
+        );
+    if let Some(token) = &creds.session_token {
+        req = req.header("x-amz-security-token", token);
+    }
+    req
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
@@ -0,0 +1,437 @@
+//! Fetching one large file as several byte ranges over parallel connections.
+
+use bytes::Bytes;
+use futures_util::future::join_all;
+use indicatif::ProgressBar;
+use reqwest::header::{CONTENT_TYPE, RANGE};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::blocks::BlockHashes;
+use crate::cache::Validators;
+use crate::download::{
+    accepts_ranges, check_remote_size, check_size, count_received, destination,
+    download_with_retries, finish, header_content_length, last_modified, next_chunk, part_path,
+    progress_bar, request, response_head, stretch_over, throttle, to_hex, verify_sha256,
+    with_retries, MinRate, Saved,
+};
+use crate::error::{DownloadError, Result, StatusError};
+use crate::hash::Hasher;
+use crate::space::SpaceClaim;
+use crate::{Options, Shared};
+
+/// How one byte range of a segmented download went.
+#[derive(Debug, Clone)]
+pub struct SegmentTiming {
+    /// First byte of the range.
+    pub start: u64,
+    /// Last byte, inclusive.
+    pub end: u64,
+    pub attempts: u32,
+    /// From its first request to its last byte, retries included.
+    pub elapsed: Duration,
+}
+
+impl SegmentTiming {
+    pub fn bytes(&self) -> u64 {
+        self.end + 1 - self.start
+    }
+}
+
+/// One byte range of the file and how much of it has landed on disk.
+struct Segment {
+    start: u64,
+    /// Inclusive, as in a `Range` header.
+    end: u64,
+    written: AtomicU64,
+}
+
+/// Download `url` in `opts.segments` concurrent ranges.
+///
+/// A `HEAD` request learns the size and whether the server accepts byte
+/// ranges; when it doesn't (or the size is unknown) this falls back to a
+/// single stream, as it does if a segment's range request gets a full `200`
+/// response after all. Compressed mode always takes the single stream, since
+/// ranges would address the encoded body, and so does any method but `GET`.
+/// Each segment retries independently and picks up from the last byte it
+/// wrote. The reported attempt count is the worst segment's.
+///
+/// With `blocks`, segments start on block boundaries (one segment is fine
+/// too), and each block is checked once its segment is in; one that doesn't
+/// match is fetched again by itself, the segment's retries allowing.
+///
+/// A file that fails `expected_sha256` once assembled has its segments
+/// fetched again one at a time, those that needed retries first, until it
+/// matches; only if none of them fixes it does the download fail.
+pub(crate) async fn download(
+    shared: &Shared,
+    url: &Url,
+    path: &Path,
+    opts: Options,
+    expected_sha256: Option<&str>,
+    blocks: Option<&BlockHashes>,
+) -> (Result<Saved>, u32) {
+    if opts.compressed || shared.method != Method::GET {
+        return download_with_retries(shared, url, path, opts, expected_sha256).await;
+    }
+    let sent = Instant::now();
+    let mut head = request(shared, Method::HEAD, url);
+    if let Some(cached) = &shared.validators {
+        head = cached.apply(head);
+    }
+    let (headers, final_url, response) = match head.send().await {
+        Ok(resp) if resp.status() == StatusCode::NOT_MODIFIED && shared.validators.is_some() => {
+            return (Err(DownloadError::NotModified), 1);
+        }
+        Ok(resp) if resp.status().is_success() => {
+            let response = opts.save_headers.then(|| response_head(&resp));
+            (resp.headers().clone(), resp.url().clone(), response)
+        }
+        _ => return download_with_retries(shared, url, path, opts, expected_sha256).await,
+    };
+    let ttfb = sent.elapsed();
+    let ranges_ok = accepts_ranges(&headers);
+    let total = header_content_length(&headers).unwrap_or(0);
+    let n = match blocks {
+        Some(blocks) => (opts.segments.max(1) as u64).min(blocks.count()),
+        None => (opts.segments as u64).min(total),
+    };
+    if !ranges_ok || (n < 2 && blocks.is_none()) || total == 0 {
+        debug!(%url, ranges_ok, total, "not segmenting");
+        return download_with_retries(shared, url, path, opts, expected_sha256).await;
+    }
+    if let Some(blocks) = blocks.filter(|b| !b.fits(total)) {
+        let e = DownloadError::Protocol(format!(
+            "{url} is {total} bytes, but its block hashes describe {} blocks of {}",
+            blocks.count(),
+            blocks.block_size
+        ));
+        return (Err(e), 1);
+    }
+
+    if let Err(e) = shared.accept.check(url, &headers) {
+        return (Err(e), 1);
+    }
+    if let Err(e) = check_remote_size(total, opts).and_then(|()| check_size(total, opts)) {
+        return (Err(e), 1);
+    }
+    let part = part_path(path);
+    let _claim = match SpaceClaim::new(shared, &part, total) {
+        Ok(claim) => claim,
+        Err(e) => return (Err(e), 1),
+    };
+
+    let dest = destination(shared, &headers, path, opts);
+    let segments: Vec<Segment> = (0..n)
+        .map(|i| {
+            let (start, end) = match blocks {
+                Some(blocks) => {
+                    let count = blocks.count();
+                    let (start, _) = blocks.range(i * count / n, total);
+                    let (_, end) = blocks.range((i + 1) * count / n - 1, total);
+                    (start, end)
+                }
+                None => (i * total / n, (i + 1) * total / n - 1),
+            };
+            Segment {
+                start,
+                end,
+                written: AtomicU64::new(0),
+            }
+        })
+        .collect();
+    debug!(%url, total, segments = n, "segmenting");
+
+    // Preallocate so every segment can write at its own offset
+    let res = async {
+        let file = fs::File::create(&part).await?;
+        file.set_len(total).await
+    }
+    .await;
+    if let Err(e) = res {
+        let context = format!("create file {}", part.display());
+        return (Err(DownloadError::io(context)(e)), 0);
+    }
+    let pb = progress_bar(shared, &dest, Some(total), opts);
+
+    let outcomes = join_all(segments.iter().map(|seg| async {
+        let started = Instant::now();
+        let (res, attempts) = with_retries(shared, url, opts, || async {
+            fetch_segment(shared, url, &part, seg, opts, &pb).await?;
+            match blocks {
+                Some(blocks) => {
+                    refetch_bad_blocks(shared, url, &part, seg, blocks, opts, &pb).await
+                }
+                None => Ok(()),
+            }
+        })
+        .await;
+        let timing = SegmentTiming {
+            start: seg.start,
+            end: seg.end,
+            attempts,
+            elapsed: started.elapsed(),
+        };
+        (res, timing)
+    }))
+    .await;
+    let attempts = outcomes.iter().map(|(_, t)| t.attempts).max().unwrap_or(0);
+
+    if outcomes
+        .iter()
+        .any(|(res, _)| matches!(res, Err(DownloadError::RangeIgnored)))
+    {
+        debug!(%url, "range ignored; falling back to a single stream");
+        pb.finish_and_clear();
+        let _ = fs::remove_file(&part).await;
+        return download_with_retries(shared, url, path, opts, expected_sha256).await;
+    }
+
+    let mut timings = Vec::with_capacity(outcomes.len());
+    let res = async {
+        for (res, timing) in outcomes {
+            res?;
+            timings.push(timing);
+        }
+        let (mut hasher, mut digest) = hash_part(&part, expected_sha256.is_some(), opts).await?;
+        if let Some(expected) = expected_sha256 {
+            // A segment that had to resume is likelier to have been garbled
+            let mut suspects: Vec<(&Segment, u32)> = segments
+                .iter()
+                .zip(timings.iter().map(|t| t.attempts))
+                .collect();
+            suspects.sort_by_key(|&(_, attempts)| Reverse(attempts));
+            for (seg, _) in suspects {
+                let actual = to_hex(&hasher.clone().expect("hashed for sha256").finalize());
+                if actual.eq_ignore_ascii_case(expected) {
+                    break;
+                }
+                refetch_segment(shared, url, &part, seg, opts, &pb).await?;
+                (hasher, digest) = hash_part(&part, true, opts).await?;
+            }
+            verify_sha256(hasher.expect("hashed for sha256"), expected, &part, &pb).await?;
+        }
+        finish(&part, &dest, last_modified(&headers), opts, &pb).await?;
+        Ok(Saved {
+            path: dest,
+            bytes: total,
+            final_url,
+            digest: digest.map(Hasher::finalize_hex),
+            content_type: headers
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            ttfb: Some(ttfb),
+            elapsed: sent.elapsed(),
+            validators: Validators::from_headers(&headers),
+            response,
+            segments: timings,
+        })
+    }
+    .await;
+
+    // A sparse, partly-filled file can't be resumed by a later single-stream
+    // run, so unlike a plain download it goes even when interrupted
+    if res.is_err() {
+        let _ = fs::remove_file(&part).await;
+    }
+    (res, attempts)
+}
+
+/// Fetch whatever is still missing from `seg` and write it at its offset.
+async fn fetch_segment(
+    shared: &Shared,
+    url: &Url,
+    part: &Path,
+    seg: &Segment,
+    opts: Options,
+    pb: &ProgressBar,
+) -> Result<()> {
+    let pos = seg.start + seg.written.load(Ordering::Relaxed);
+    if pos > seg.end {
+        return Ok(());
+    }
+
+    let req = request(shared, Method::GET, url).header(RANGE, format!("bytes={pos}-{}", seg.end));
+    let resp = tokio::select! {
+        _ = shared.cancel.cancelled() => return Err(DownloadError::Interrupted),
+        resp = req.send() => resp.map_err(DownloadError::network(url))?,
+    };
+    if !resp.status().is_success() {
+        return Err(StatusError::from_response(&resp).into());
+    }
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::RangeIgnored);
+    }
+
+    let write_err = || DownloadError::io(format!("write {}", part.display()));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(part)
+        .await
+        .map_err(DownloadError::io(format!("open file {}", part.display())))?;
+    file.seek(SeekFrom::Start(pos)).await.map_err(write_err())?;
+    let mut file = BufWriter::with_capacity(opts.buffer_size, file);
+
+    let mut remaining = seg.end + 1 - pos;
+    let mut stream = resp.bytes_stream();
+    // Each segment has to keep up its share of the file's minimum
+    let mut pace = MinRate::new(opts, opts.segments as u64);
+    let res = async {
+        loop {
+            let chunk = tokio::select! {
+                biased;
+                _ = shared.cancel.cancelled() => return Err(DownloadError::Interrupted),
+                chunk = next_chunk(&mut stream, opts, url, seg.end + 1 - remaining) => chunk?,
+                () = stretch_over(&pace) => Some(Bytes::new()),
+            };
+            if let (Some(p), Some(c)) = (&mut pace, &chunk) {
+                p.add(c.len() as u64, url)?;
+            }
+            let Some(chunk) = chunk else { break };
+            // Never spill into the neighbouring segment, whatever the server sends
+            let chunk = &chunk[..chunk.len().min(remaining as usize)];
+            throttle(shared, chunk.len() as u64).await;
+            file.write_all(chunk).await.map_err(write_err())?;
+            seg.written.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            pb.inc(chunk.len() as u64);
+            count_received(shared, opts, chunk.len() as u64)?;
+            remaining -= chunk.len() as u64;
+            if remaining == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+    .await;
+    // `written` already counts whatever is still buffered, so it has to
+    // reach the disk however this attempt ended
+    file.flush().await.map_err(write_err())?;
+    res?;
+
+    if remaining > 0 {
+        return Err(DownloadError::Protocol(format!(
+            "segment {}-{} of {url} ended {remaining} bytes early",
+            seg.start, seg.end
+        )));
+    }
+    Ok(())
+}
+
+/// Check the blocks `seg` covers, now on disk, and fetch any that don't
+/// match again. A block still wrong after that fails this attempt, so the
+/// next one starts over from the checks.
+async fn refetch_bad_blocks(
+    shared: &Shared,
+    url: &Url,
+    part: &Path,
+    seg: &Segment,
+    blocks: &BlockHashes,
+    opts: Options,
+    pb: &ProgressBar,
+) -> Result<()> {
+    let (mut first, last) = (seg.start / blocks.block_size, seg.end / blocks.block_size);
+    // Segments end on block boundaries, so only the last block of the last
+    // one is cut short, by the end of the file
+    let total = seg.end + 1;
+    while let Some((i, actual)) = blocks.first_mismatch(part, first, last, total).await? {
+        let (start, end) = blocks.range(i, total);
+        info!(%url, block = i, actual, "block mismatch; fetching it again");
+        if !opts.quiet {
+            shared
+                .multi
+                .suspend(|| eprintln!("block {i} of {url} is corrupt; fetching it again"));
+        }
+        pb.dec(end + 1 - start);
+        let block = Segment {
+            start,
+            end,
+            written: AtomicU64::new(0),
+        };
+        fetch_segment(shared, url, part, &block, opts, pb).await?;
+        blocks.verify(part, i, total).await?;
+        first = i + 1;
+    }
+    Ok(())
+}
+
+/// Fetch all of `seg` again over what's on disk, for an assembled file that
+/// failed its checksum.
+async fn refetch_segment(
+    shared: &Shared,
+    url: &Url,
+    part: &Path,
+    seg: &Segment,
+    opts: Options,
+    pb: &ProgressBar,
+) -> Result<()> {
+    info!(%url, start = seg.start, end = seg.end, "checksum mismatch; fetching segment again");
+    if !opts.quiet {
+        shared.multi.suspend(|| {
+            eprintln!(
+                "sha256 mismatch for {url}; fetching bytes {}-{} again",
+                seg.start, seg.end
+            )
+        });
+    }
+    pb.dec(seg.end + 1 - seg.start);
+    let fresh = Segment {
+        start: seg.start,
+        end: seg.end,
+        written: AtomicU64::new(0),
+    };
+    let (res, _) = with_retries(shared, url, opts, || {
+        fetch_segment(shared, url, part, &fresh, opts, pb)
+    })
+    .await;
+    res
+}
+
+/// The SHA-256 (if `sha256`) and `opts.digest` of the file at `part`.
+async fn hash_part(
+    part: &Path,
+    sha256: bool,
+    opts: Options,
+) -> Result<(Option<Sha256>, Option<Hasher>)> {
+    let mut hasher = sha256.then(Sha256::new);
+    let mut digest = opts.digest.map(Hasher::new);
+    if hasher.is_some() || digest.is_some() {
+        hash_file(part, |buf| {
+            if let Some(h) = hasher.as_mut() {
+                h.update(buf);
+            }
+            if let Some(d) = digest.as_mut() {
+                d.update(buf);
+            }
+        })
+        .await?;
+    }
+    Ok((hasher, digest))
+}
+
+/// Segments arrive out of order, so digests have to be taken from disk:
+/// `update` sees the whole file, a block at a time.
+async fn hash_file(path: &Path, mut update: impl FnMut(&[u8])) -> Result<()> {
+    let read_err = || DownloadError::io(format!("read {}", path.display()));
+    let mut file = fs::File::open(path).await.map_err(read_err())?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await.map_err(read_err())?;
+        if n == 0 {
+            break;
+        }
+        update(&buf[..n]);
+    }
+    Ok(())
+}
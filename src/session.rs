@@ -0,0 +1,133 @@
+//! A batch's progress saved to disk, so an interrupted job can pick up where
+//! it stopped.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use url::Url;
+
+use crate::download::part_path;
+use crate::error::DownloadError;
+use crate::{DownloadResult, Status};
+
+/// Where one URL stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum State {
+    Pending,
+    InProgress,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    state: State,
+    /// Where the file goes; a resumed run reuses it so the `.part` file is
+    /// found again.
+    path: PathBuf,
+    /// Bytes on disk when the entry last changed.
+    bytes: u64,
+}
+
+/// Per-URL state of a batch, rewritten to a JSON file on every change.
+///
+/// The file is replaced atomically, so a crash leaves either the old or the
+/// new state behind, never half of one.
+#[derive(Debug)]
+pub struct Session {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl Session {
+    /// Record the batch in `path`. With `resume`, start from what an earlier
+    /// run left there, if anything; otherwise start over.
+    pub fn open(path: impl Into<PathBuf>, resume: bool) -> Result<Self> {
+        let path = path.into();
+        let entries = match std::fs::read(&path) {
+            Ok(data) if resume => serde_json::from_slice(&data)
+                .with_context(|| format!("parse session file {}", path.display()))?,
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("read session file {}", path.display()))
+            }
+            _ => BTreeMap::new(),
+        };
+        Ok(Session {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Where an earlier run put `url`, and how far it got.
+    pub(crate) fn get(&self, url: &Url) -> Option<(State, PathBuf)> {
+        let entries = self.entries.lock().unwrap();
+        let e = entries.get(url.as_str())?;
+        Some((e.state, e.path.clone()))
+    }
+
+    /// Note every URL of a batch that isn't known yet as pending.
+    pub(crate) fn add(&self, urls: &[(Url, PathBuf)]) -> Result<(), DownloadError> {
+        let mut entries = self.entries.lock().unwrap();
+        for (url, path) in urls {
+            entries.entry(url.to_string()).or_insert_with(|| Entry {
+                state: State::Pending,
+                path: path.clone(),
+                bytes: 0,
+            });
+        }
+        self.save(&entries)
+    }
+
+    /// `url` now holds a download slot.
+    pub(crate) fn start(&self, url: &Url, path: &Path) -> Result<(), DownloadError> {
+        let bytes = std::fs::metadata(part_path(path)).map_or(0, |m| m.len());
+        self.set(url, State::InProgress, path, bytes)
+    }
+
+    /// Record how `res` ended. An interruption stays in progress, with the
+    /// `.part` file's size, so the next run resumes it.
+    pub(crate) fn finish(&self, res: &DownloadResult) -> Result<(), DownloadError> {
+        let (state, bytes) = match res.status {
+            Status::Saved | Status::UpToDate | Status::NotModified => {
+                let len = std::fs::metadata(&res.path).map_or(res.bytes, |m| m.len());
+                (State::Done, len)
+            }
+            Status::Failed => (State::Failed, 0),
+            Status::Interrupted => {
+                let len = std::fs::metadata(part_path(&res.path)).map_or(0, |m| m.len());
+                (State::InProgress, len)
+            }
+            Status::Skipped | Status::Filtered | Status::Planned => (State::Pending, 0),
+        };
+        self.set(&res.url, state, &res.path, bytes)
+    }
+
+    fn set(&self, url: &Url, state: State, path: &Path, bytes: u64) -> Result<(), DownloadError> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(
+            url.to_string(),
+            Entry {
+                state,
+                path: path.to_path_buf(),
+                bytes,
+            },
+        );
+        self.save(&entries)
+    }
+
+    /// Write to a temporary file next to the session, then rename it over.
+    fn save(&self, entries: &BTreeMap<String, Entry>) -> Result<(), DownloadError> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let json = serde_json::to_vec_pretty(entries).expect("session entries serialize");
+        std::fs::write(&tmp, json)
+            .and_then(|()| std::fs::rename(&tmp, &self.path))
+            .map_err(DownloadError::io(format!(
+                "write session file {}",
+                self.path.display()
+            )))
+    }
+}
@@ -0,0 +1,57 @@
+//! Making sure a download fits on disk before writing it.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::error::{DownloadError, Result};
+use crate::Shared;
+
+/// Bytes a download has promised to write, counted against free space for
+/// every other download until they land on disk or the claim is dropped.
+pub(crate) struct SpaceClaim {
+    pending: Arc<AtomicU64>,
+    remaining: u64,
+}
+
+impl SpaceClaim {
+    /// Claim `bytes` for `file`, failing if they plus everything other
+    /// downloads still expect to write won't fit in its directory. A
+    /// filesystem that can't report free space isn't checked.
+    pub(crate) fn new(shared: &Shared, file: &Path, bytes: u64) -> Result<Self> {
+        let dir = match file.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new("."),
+        };
+        let others = shared.pending_bytes.fetch_add(bytes, Ordering::SeqCst);
+        let claim = SpaceClaim {
+            pending: shared.pending_bytes.clone(),
+            remaining: bytes,
+        };
+        if let Ok(available) = fs2::available_space(dir) {
+            let needed = others + bytes;
+            if needed > available {
+                return Err(DownloadError::InsufficientSpace {
+                    dir: dir.to_path_buf(),
+                    needed,
+                    others,
+                    available,
+                });
+            }
+        }
+        Ok(claim)
+    }
+
+    /// `bytes` of the claim are now on disk and show up in free space instead.
+    pub(crate) fn wrote(&mut self, bytes: u64) {
+        let bytes = bytes.min(self.remaining);
+        self.remaining -= bytes;
+        self.pending.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+impl Drop for SpaceClaim {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(self.remaining, Ordering::SeqCst);
+    }
+}
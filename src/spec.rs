@@ -0,0 +1,66 @@
+//! One URL together with the settings that apply to it alone.
+
+use reqwest::header::HeaderMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
+
+/// A URL to download, with overrides for just this file. Anything left
+/// `None` or empty falls back to the [`Downloader`](crate::Downloader)'s own
+/// settings.
+#[derive(Debug, Clone)]
+pub struct DownloadSpec {
+    pub url: Url,
+    /// Path under the output directory to save to, used exactly as given:
+    /// no `(N)` suffix, server-suggested name or added extension.
+    pub output: Option<PathBuf>,
+    /// File name to save as in place of the one taken from the URL. Unlike
+    /// `output` it's sanitized and numbered on a clash like any other name,
+    /// though a server-suggested name or added extension still can't
+    /// replace it.
+    pub name: Option<String>,
+    /// Sent with every request for this file, replacing any default header
+    /// of the same name.
+    pub headers: HeaderMap,
+    /// Expected SHA-256 (hex), in place of a checksums-file lookup.
+    pub sha256: Option<String>,
+    /// Tried in order when `url` fails, in place of the configured mirrors.
+    pub mirrors: Vec<Url>,
+    /// Size limit in bytes for this file.
+    pub max_size: Option<u64>,
+    /// Attempts for this file, the first one included.
+    pub retries: Option<u32>,
+    /// Base backoff between attempts, in milliseconds.
+    pub backoff_ms: Option<u64>,
+    /// Limit on each whole request, in place of the client's timeout.
+    pub timeout: Option<Duration>,
+    /// Download slots this file takes up (default 1), so a heavy transfer
+    /// can leave less room for others. Capped at the concurrency, and
+    /// ignored under auto-concurrency, whose pool can shrink below it.
+    pub weight: usize,
+}
+
+impl DownloadSpec {
+    /// `url` with nothing overridden.
+    pub fn new(url: Url) -> Self {
+        DownloadSpec {
+            url,
+            output: None,
+            name: None,
+            headers: HeaderMap::new(),
+            sha256: None,
+            mirrors: Vec::new(),
+            max_size: None,
+            retries: None,
+            backoff_ms: None,
+            timeout: None,
+            weight: 1,
+        }
+    }
+}
+
+impl From<Url> for DownloadSpec {
+    fn from(url: Url) -> Self {
+        DownloadSpec::new(url)
+    }
+}
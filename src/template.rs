@@ -0,0 +1,128 @@
+//! `--output-template`: naming saved files from placeholders.
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+use url::Url;
+
+use crate::download::to_hex;
+use crate::naming::{sanitize_file_name, saved_name_from_url};
+
+/// A parsed output file name template such as `{host}/{date}-{filename}`.
+///
+/// | placeholder  | value                                          |
+/// |--------------|------------------------------------------------|
+/// | `{host}`     | the URL's host                                 |
+/// | `{filename}` | last path segment, as without a template       |
+/// | `{stem}`     | `{filename}` without its extension             |
+/// | `{ext}`      | its extension, without the dot                 |
+/// | `{index}`    | 1-based position of the URL in the batch       |
+/// | `{date}`     | today as `YYYY-MM-DD` (UTC)                    |
+/// | `{hash}`     | first 12 hex digits of the URL's SHA-256       |
+///
+/// `/` separates directories, `{{` and `}}` are literal braces. Every rendered
+/// path component is sanitized like a `Content-Disposition` name, so a
+/// placeholder can never climb out of the output directory.
+#[derive(Debug, Clone)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Host,
+    Filename,
+    Stem,
+    Ext,
+    Index,
+    Date,
+    Hash,
+}
+
+impl FromStr for OutputTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(d) => name.push(d),
+                            None => bail!("unmatched '{{' in '{s}'"),
+                        }
+                    }
+                    let part = match name.as_str() {
+                        "host" => Part::Host,
+                        "filename" => Part::Filename,
+                        "stem" => Part::Stem,
+                        "ext" => Part::Ext,
+                        "index" => Part::Index,
+                        "date" => Part::Date,
+                        "hash" => Part::Hash,
+                        _ => bail!("unknown placeholder '{{{name}}}' in '{s}'"),
+                    };
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    parts.push(part);
+                }
+                '}' => bail!("unmatched '}}' in '{s}'"),
+                _ => literal.push(c),
+            }
+        }
+        parts.push(Part::Literal(literal));
+        Ok(OutputTemplate { parts })
+    }
+}
+
+impl OutputTemplate {
+    /// The relative path `url`, the `index`th URL of its batch, renders to.
+    pub(crate) fn render(&self, url: &Url, index: usize) -> PathBuf {
+        let filename = saved_name_from_url(url);
+        let (stem, ext) = match filename.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+            _ => (filename.as_str(), ""),
+        };
+
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => out.push_str(s),
+                Part::Host => out.push_str(url.host_str().unwrap_or("localhost")),
+                Part::Filename => out.push_str(&filename),
+                Part::Stem => out.push_str(stem),
+                Part::Ext => out.push_str(ext),
+                Part::Index => out.push_str(&index.to_string()),
+                Part::Date => {
+                    let now = humantime::format_rfc3339(SystemTime::now()).to_string();
+                    out.push_str(&now[..10]);
+                }
+                Part::Hash => {
+                    let digest = to_hex(&Sha256::digest(url.as_str()));
+                    out.push_str(&digest[..12]);
+                }
+            }
+        }
+
+        let path: PathBuf = out.split('/').filter_map(sanitize_file_name).collect();
+        if path.as_os_str().is_empty() {
+            return PathBuf::from("download");
+        }
+        path
+    }
+}
@@ -0,0 +1,213 @@
+//! `--tui`: a full-screen dashboard in place of the stacked progress bars.
+
+use indicatif::{HumanBytes, HumanDuration};
+use mt_downloader::{Monitor, Transfer, TransferState};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph, Row, Table, TableState};
+use ratatui::Frame;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// How often the screen is redrawn.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Rows are grouped by state, in this order.
+const SECTIONS: [TransferState; 5] = [
+    TransferState::Active,
+    TransferState::Queued,
+    TransferState::Failed,
+    TransferState::Cancelled,
+    TransferState::Done,
+];
+
+/// Draw `monitor` until `stop` is cancelled. `q` or Ctrl-C cancels the batch
+/// through `cancel`, `c` the selected transfer; arrows select, PgUp/PgDn
+/// scroll the log.
+pub fn run(
+    monitor: Arc<Monitor>,
+    cancel: CancellationToken,
+    stop: CancellationToken,
+    color: bool,
+) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut table = TableState::default().with_selected(0);
+    // Lines scrolled up from the end of the log
+    let mut log_back = 0usize;
+
+    let res = loop {
+        if stop.is_cancelled() {
+            break Ok(());
+        }
+        let mut transfers: Vec<(usize, Transfer)> =
+            monitor.transfers().into_iter().enumerate().collect();
+        transfers.sort_by_key(|(i, t)| (SECTIONS.iter().position(|s| *s == t.state), *i));
+        let log = monitor.log();
+        if let Err(e) = terminal.draw(|f| draw(f, &transfers, &log, log_back, &mut table, color)) {
+            break Err(e);
+        }
+
+        match event::poll(TICK) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(e) => break Err(e),
+        }
+        let key = match event::read() {
+            Ok(Event::Key(k)) if k.kind == KeyEventKind::Press => k,
+            Ok(_) => continue,
+            Err(e) => break Err(e),
+        };
+        match key.code {
+            KeyCode::Char('q') => cancel.cancel(),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => cancel.cancel(),
+            KeyCode::Char('c') => {
+                if let Some((index, _)) = table.selected().and_then(|i| transfers.get(i)) {
+                    monitor.cancel(*index);
+                }
+            }
+            KeyCode::Down | KeyCode::Char('j') => table.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => table.select_previous(),
+            KeyCode::PageUp => log_back = (log_back + 5).min(log.len().saturating_sub(1)),
+            KeyCode::PageDown => log_back = log_back.saturating_sub(5),
+            _ => {}
+        }
+    };
+    ratatui::restore();
+    res
+}
+
+fn draw(
+    f: &mut Frame,
+    transfers: &[(usize, Transfer)],
+    log: &[String],
+    log_back: usize,
+    table: &mut TableState,
+    color: bool,
+) {
+    let style = |c: Color| {
+        if color {
+            Style::new().fg(c)
+        } else {
+            Style::new()
+        }
+    };
+    let [top, middle, bottom, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(5),
+        Constraint::Length(8),
+        Constraint::Length(1),
+    ])
+    .areas(f.area());
+
+    // Totals
+    let count = |state| transfers.iter().filter(|(_, t)| t.state == state).count();
+    let finished = transfers.len() - count(TransferState::Queued) - count(TransferState::Active);
+    let received: u64 = transfers.iter().map(|(_, t)| t.received).sum();
+    let rate: f64 = transfers
+        .iter()
+        .filter(|(_, t)| t.state == TransferState::Active)
+        .map(|(_, t)| t.bytes_per_sec)
+        .sum();
+    let title = format!(
+        " {} active, {} queued, {} done, {} failed, {} cancelled ",
+        count(TransferState::Active),
+        count(TransferState::Queued),
+        count(TransferState::Done),
+        count(TransferState::Failed),
+        count(TransferState::Cancelled),
+    );
+    let ratio = finished as f64 / transfers.len().max(1) as f64;
+    f.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(title))
+            .gauge_style(style(Color::Green))
+            .ratio(ratio)
+            .label(format!(
+                "{finished}/{} files, {} at {}/s",
+                transfers.len(),
+                HumanBytes(received),
+                HumanBytes(rate as u64)
+            )),
+        top,
+    );
+
+    // One row per transfer
+    let rows = transfers.iter().map(|(_, t)| {
+        let (state, c) = match t.state {
+            TransferState::Queued => ("queued", Color::Gray),
+            TransferState::Active => ("active", Color::Cyan),
+            TransferState::Done => ("done", Color::Green),
+            TransferState::Failed => ("failed", Color::Red),
+            TransferState::Cancelled => ("cancelled", Color::Yellow),
+        };
+        let name = t
+            .path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| t.url.to_string());
+        let progress = match t.total {
+            Some(total) if total > 0 => format!(
+                "{:>3}% {}/{}",
+                t.received * 100 / total,
+                HumanBytes(t.received),
+                HumanBytes(total)
+            ),
+            _ => HumanBytes(t.received).to_string(),
+        };
+        let (speed, eta) = match t.state {
+            TransferState::Active => (
+                format!("{}/s", HumanBytes(t.bytes_per_sec as u64)),
+                t.eta
+                    .map(|d| HumanDuration(d).to_string())
+                    .unwrap_or_default(),
+            ),
+            _ => Default::default(),
+        };
+        let detail = t.error.clone().unwrap_or_else(|| t.url.to_string());
+        Row::new(vec![state.to_string(), name, progress, speed, eta, detail]).style(style(c))
+    });
+    f.render_stateful_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Length(9),
+                Constraint::Percentage(25),
+                Constraint::Length(26),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(
+            Row::new(["state", "file", "progress", "speed", "eta", "url / error"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::bordered().title(" downloads "))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+        middle,
+        table,
+    );
+
+    // Newest log lines at the bottom, unless scrolled back
+    let height = bottom.height.saturating_sub(2) as usize;
+    let end = log.len().saturating_sub(log_back);
+    let lines: Vec<Line> = log[end.saturating_sub(height)..end]
+        .iter()
+        .map(|l| Line::raw(l.as_str()))
+        .collect();
+    f.render_widget(
+        Paragraph::new(lines)
+            .style(style(Color::Red))
+            .block(Block::bordered().title(format!(" retries and errors ({}) ", log.len()))),
+        bottom,
+    );
+
+    f.render_widget(
+        Line::raw(" up/down select   c cancel selected   PgUp/PgDn scroll log   q cancel all"),
+        help,
+    );
+}